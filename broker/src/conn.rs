@@ -2,16 +2,44 @@ use anyhow::Result;
 use once_cell::sync::Lazy;
 use rocket::tokio::sync::{mpsc, oneshot};
 use serde::{Deserialize, Serialize};
+use sphinx_key_common::ext::{Approval, ApprovalState, HeapWarning, Restart};
+use sphinx_key_common::hello::Hello;
+use sphinx_key_common::ota::Progress;
 use std::collections::HashMap; // 1.3.1
 use std::sync::Mutex;
 
 pub static CONNS: Lazy<Mutex<Connections>> = Lazy::new(|| Mutex::new(Connections::new()));
 
+// for each client
+const MAX_RESTARTS: usize = 16;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Connections {
     pub pubkey: Option<String>,
     pub clients: HashMap<String, bool>, // bool is "synced" state (done with dance)
     pub current: Option<String>,
+    pub info: HashMap<String, ClientInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ClientInfo {
+    // what the client announced in its HELLO
+    pub hello: Option<Hello>,
+    // the negotiated protocol version
+    pub protocol: Option<u16>,
+    // why the client was refused, if it was
+    pub error: Option<String>,
+    // the last OTA progress reported
+    pub ota: Option<Progress>,
+    // payments waiting on the owner
+    #[serde(default)]
+    pub approvals: Vec<Approval>,
+    // why it restarted, oldest first
+    #[serde(default)]
+    pub restarts: Vec<Restart>,
+    // the last change in heap level reported
+    #[serde(default)]
+    pub heap: Option<HeapWarning>,
 }
 
 impl Connections {
@@ -20,6 +48,7 @@ impl Connections {
             pubkey: None,
             clients: HashMap::new(),
             current: None,
+            info: HashMap::new(),
         }
     }
     fn connect_client(&mut self, cid: &str, synced: bool) {
@@ -68,6 +97,75 @@ pub fn new_connection(cid: &str, connected: bool) {
     cs.connect_client(cid, connected);
 }
 
+pub fn client_accepted(cid: &str, hello: Hello, protocol: u16) {
+    let mut cs = CONNS.lock().unwrap();
+    let info = cs.info.entry(cid.to_string()).or_default();
+    info.hello = Some(hello);
+    info.protocol = Some(protocol);
+    info.error = None;
+}
+
+pub fn client_refused(cid: &str, hello: Option<Hello>, error: String) {
+    let mut cs = CONNS.lock().unwrap();
+    let info = cs.info.entry(cid.to_string()).or_default();
+    info.hello = hello;
+    info.protocol = None;
+    info.error = Some(error);
+}
+
+// false for a client that hasn't said HELLO, or was refused
+pub fn client_has(cid: &str, cap: &str) -> bool {
+    let cs = CONNS.lock().unwrap();
+    cs.info
+        .get(cid)
+        .filter(|i| i.protocol.is_some())
+        .and_then(|i| i.hello.as_ref())
+        .is_some_and(|h| h.has(cap))
+}
+
+pub fn client_ota_progress(cid: &str, progress: Option<Progress>) {
+    let mut cs = CONNS.lock().unwrap();
+    cs.info.entry(cid.to_string()).or_default().ota = progress;
+}
+
+pub fn client_ota_status(cid: &str) -> Option<Progress> {
+    let cs = CONNS.lock().unwrap();
+    cs.info.get(cid).and_then(|i| i.ota)
+}
+
+// pending ones are added or replaced, decided and expired ones removed
+pub fn client_approval(cid: &str, approval: Approval) {
+    let mut cs = CONNS.lock().unwrap();
+    let approvals = &mut cs.info.entry(cid.to_string()).or_default().approvals;
+    approvals.retain(|a| a.id != approval.id);
+    if approval.state == ApprovalState::Pending {
+        approvals.push(approval);
+    }
+}
+
+// the last few are kept
+pub fn client_restart(cid: &str, restart: Restart) {
+    let mut cs = CONNS.lock().unwrap();
+    let restarts = &mut cs.info.entry(cid.to_string()).or_default().restarts;
+    restarts.push(restart);
+    let extra = restarts.len().saturating_sub(MAX_RESTARTS);
+    restarts.drain(..extra);
+}
+
+pub fn client_heap(cid: &str, warning: HeapWarning) {
+    let mut cs = CONNS.lock().unwrap();
+    cs.info.entry(cid.to_string()).or_default().heap = Some(warning);
+}
+
+pub fn client_approvals() -> HashMap<String, Vec<Approval>> {
+    let cs = CONNS.lock().unwrap();
+    cs.info
+        .iter()
+        .filter(|(_, i)| !i.approvals.is_empty())
+        .map(|(cid, i)| (cid.clone(), i.approvals.clone()))
+        .collect()
+}
+
 pub fn cycle_clients(cid: &str) {
     let mut cs = CONNS.lock().unwrap();
     let clients = cs.clients.clone();
@@ -1,10 +1,22 @@
-use crate::conn::{ChannelReply, ChannelRequest};
+use crate::conn::{
+    client_accepted, client_approval, client_heap, client_ota_progress, client_refused,
+    client_restart, ChannelReply, ChannelRequest,
+};
 use crate::util::Settings;
 use rocket::tokio::{sync::broadcast, sync::mpsc, task::JoinSet};
-use rumqttd::{local::LinkTx, AuthMsg, Broker, Config, Notification};
+use rumqttd::local::{LinkError, LinkTx};
+use rumqttd::protocol::{Packet, Publish, QoS};
+use rumqttd::{AuthMsg, Broker, Config, Notification};
+use sphinx_key_common::hello::{caps, Hello};
+use sphinx_key_common::ota::{self as ota_proto, Progress};
+use sphinx_key_common::topics as common_topics;
 use sphinx_signer::sphinx_glyph::sphinx_auther::token::Token;
 use sphinx_signer::sphinx_glyph::topics;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+// packet ids for the QoS 1 publishes from the local link
+static PKID: AtomicU16 = AtomicU16::new(1u16);
 
 // must get a reply within this time, or disconnects
 // const REPLY_TIMEOUT_MS: u64 = 10000;
@@ -67,7 +79,9 @@ pub fn start_broker(
     let _relay_task = task_set.spawn_blocking(move || {
         while let Some(msg) = receiver.blocking_recv() {
             log::debug!("Received message here: {:?}", msg);
-            let retries = if msg.topic == topics::CONTROL {
+            let is_control =
+                msg.topic == topics::CONTROL || msg.topic == common_topics::EXT_CONTROL;
+            let retries = if is_control {
                 // Don't retry
                 Some(0)
             } else {
@@ -105,9 +119,33 @@ pub fn start_broker(
                     let topic_end = ts[1].to_string();
 
                     if topic.ends_with(topics::HELLO) {
-                        let _ = internal_status_tx.send((true, cid));
+                        if negotiate(&cid, &f.publish.payload) {
+                            let _ = internal_status_tx.send((true, cid));
+                        }
                     } else if topic.ends_with(topics::BYE) {
                         let _ = internal_status_tx.send((false, cid));
+                    } else if topic_end == common_topics::OTA_PROGRESS {
+                        // unsolicited, not the reply to any request
+                        match Progress::from_slice(&f.publish.payload) {
+                            Some(p) => client_ota_progress(&cid, Some(p)),
+                            None => log::warn!("malformed OTA progress from {}", cid),
+                        }
+                    } else if topic_end == common_topics::APPROVAL {
+                        // unsolicited as well
+                        match serde_json::from_slice(&f.publish.payload) {
+                            Ok(a) => client_approval(&cid, a),
+                            Err(_) => log::warn!("malformed approval from {}", cid),
+                        }
+                    } else if topic_end == common_topics::RESTART {
+                        match serde_json::from_slice(&f.publish.payload) {
+                            Ok(r) => client_restart(&cid, r),
+                            Err(_) => log::warn!("malformed restart from {}", cid),
+                        }
+                    } else if topic_end == common_topics::HEAP {
+                        match serde_json::from_slice(&f.publish.payload) {
+                            Ok(w) => client_heap(&cid, w),
+                            Err(_) => log::warn!("malformed heap warning from {}", cid),
+                        }
                     } else {
                         // VLS, CONTROL, LSS
                         let pld = f.publish.payload.to_vec();
@@ -138,6 +176,46 @@ pub fn start_broker(
     Ok(())
 }
 
+// check the HELLO of a new client, and record the outcome in the registry
+fn negotiate(cid: &str, payload: &[u8]) -> bool {
+    let hello = if payload.is_empty() {
+        Hello::legacy()
+    } else {
+        match serde_json::from_slice::<Hello>(payload) {
+            Ok(h) => h,
+            Err(e) => {
+                let err = format!("unreadable HELLO: {}", e);
+                log::error!("refusing client {}: {}", cid, err);
+                client_refused(cid, None, err);
+                return false;
+            }
+        }
+    };
+    let ours = Hello::new(
+        &[caps::LSS, caps::QOS1, caps::TELEMETRY, caps::OTA_MQTT],
+        Some(env!("CARGO_PKG_VERSION")),
+    );
+    let mut required = Vec::new();
+    if std::env::var("VLS_LSS").is_ok() {
+        required.push(caps::LSS);
+    }
+    match hello.negotiate(&ours, &required) {
+        Ok(protocol) => {
+            log::info!("client {} speaks protocol v{}", cid, protocol);
+            if !hello.has(caps::QOS1) {
+                log::warn!("client {} has no QoS 1, messages may be lost", cid);
+            }
+            client_accepted(cid, hello, protocol);
+            true
+        }
+        Err(err) => {
+            log::error!("refusing client {}: {}", cid, err);
+            client_refused(cid, Some(hello), err);
+            false
+        }
+    }
+}
+
 // waits forever until the reply is returned
 fn pub_and_wait(
     msg: ChannelRequest,
@@ -183,27 +261,111 @@ fn pub_timeout(
 ) -> Option<ChannelReply> {
     let pub_topic = format!("{}/{}", client_id, topic);
     log::info!("SENDING TO {} on topic {}", client_id, topic);
-    if let Err(e) = link_tx.publish(pub_topic, payload.to_vec()) {
+    if let Err(e) = publish(link_tx, pub_topic, payload.to_vec()) {
         log::error!("failed to pub to link_tx! {:?}", e);
     }
+    let sequence = if topic == topics::VLS {
+        serial_sequence(payload)
+    } else {
+        None
+    };
+    let expected = reply_topics(topic);
+    // the offset of an OTA chunk, to tell its ack from a late one
+    let chunk_offset = if topic == common_topics::OTA_CHUNK {
+        ota_proto::parse_chunk(payload).map(|(offset, _, _)| offset)
+    } else {
+        None
+    };
     // and receive from the correct client (or timeout to next)
-    let dur = Duration::from_secs(10);
-    if let Ok((cid, topic_end, reply)) = msg_rx.recv_timeout(dur) {
-        if cid == client_id {
-            return Some(ChannelReply::new(topic_end, reply));
-        } else {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while let Some(dur) = deadline.checked_duration_since(Instant::now()) {
+        let Ok((cid, topic_end, reply)) = msg_rx.recv_timeout(dur) else {
+            break;
+        };
+        if cid != client_id {
             log::warn!("Mismatched client id!");
             // wait a second before trying again
             std::thread::sleep(Duration::from_secs(1));
+            break;
+        }
+        // a late reply to an earlier request must not answer this one
+        if expected.is_some_and(|e| !e.contains(&topic_end.as_str())) {
+            log::info!("dropping reply on {} to a {} request", topic_end, topic);
+            continue;
+        }
+        // an ack for an earlier chunk, redelivered or late, says nothing
+        // about this one. An empty ack is a stop and still goes through
+        if let (Some(offset), Some(next)) = (chunk_offset, ota_proto::parse_ack(&reply)) {
+            if next <= offset {
+                log::info!("dropping stale ota ack {} at offset {}", next, offset);
+                continue;
+            }
         }
+        // QoS 1 can deliver a reply twice. A VLS reply is matched to its
+        // request by the sequence, so a copy of an earlier one is dropped.
+        // Other replies carry nothing to match on, and two alike are often
+        // both real, like repeated policy queries, so they aren't deduped
+        if topic_end == topics::VLS_RES && sequence.is_some() {
+            let reply_sequence = serial_sequence(&reply);
+            if reply_sequence != sequence {
+                log::info!(
+                    "dropping stale reply, sequence {:?} expected {:?}",
+                    reply_sequence,
+                    sequence
+                );
+                continue;
+            }
+        }
+        return Some(ChannelReply::new(topic_end, reply));
     }
     None
 }
 
+// the signer subscribes with QoS 1, so publish with QoS 1 as well,
+// otherwise the router downgrades the delivery to at most once
+fn publish(link_tx: &mut LinkTx, topic: String, payload: Vec<u8>) -> Result<usize, LinkError> {
+    let mut pkid = PKID.fetch_add(1u16, Ordering::SeqCst);
+    if pkid == 0 {
+        pkid = PKID.fetch_add(1u16, Ordering::SeqCst);
+    }
+    let publish = Publish {
+        dup: false,
+        qos: QoS::AtLeastOnce,
+        retain: false,
+        topic: topic.into(),
+        pkid,
+        payload: payload.into(),
+    };
+    link_tx.push(Packet::Publish(publish, None))
+}
+
+// VLS serial requests and replies start with magic (2 bytes) | sequence (2 bytes)
+// the reply topics a request can be answered on. None for topics
+// that aren't known here, whose replies are passed through as they are
+fn reply_topics(topic: &str) -> Option<&'static [&'static str]> {
+    match topic {
+        topics::VLS => Some(&[topics::VLS_RES, topics::LSS_RES]),
+        topics::CONTROL => Some(&[topics::CONTROL_RES]),
+        common_topics::EXT_CONTROL => Some(&[common_topics::EXT_CONTROL_RES]),
+        common_topics::OTA_CHUNK => Some(&[common_topics::OTA_CHUNK_RES]),
+        _ => None,
+    }
+}
+
+fn serial_sequence(payload: &[u8]) -> Option<u16> {
+    if payload.len() < 4 {
+        return None;
+    }
+    Some(u16::from_be_bytes([payload[2], payload[3]]))
+}
+
 fn subs(cid: &str, mut ltx: LinkTx) {
     for t in topics::BROKER_SUBS {
         ltx.subscribe(format!("{}/{}", cid, t)).unwrap();
     }
+    for t in common_topics::BROKER_SUBS {
+        ltx.subscribe(format!("{}/{}", cid, t)).unwrap();
+    }
 }
 
 fn unsubs(_cid: &str, mut _ltx: LinkTx) {
@@ -1,5 +1,9 @@
-use crate::conn::{current_conns, ChannelRequest};
+use crate::conn::{
+    client_approvals, client_has, client_ota_progress, client_ota_status, current_conns,
+    ChannelRequest,
+};
 use crate::util::Settings;
+use rocket::data::{Data, ToByteUnit};
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::Header;
 use rocket::response::stream::{Event, EventStream};
@@ -9,6 +13,11 @@ use rocket::tokio::sync::{
     mpsc::Sender,
 };
 use rocket::*;
+use sphinx_key_common::hello::caps;
+use sphinx_key_common::ota::{self as ota_proto, CHUNK_LEN, MAX_IMAGE_LEN};
+use sphinx_key_common::topics as common_topics;
+use sphinx_signer::sphinx_glyph::control::ControlResponse;
+use sphinx_signer::sphinx_glyph::ser::{deserialize_controlresponse, Bytes};
 use sphinx_signer::sphinx_glyph::{error::Error as GlyphError, topics};
 use std::net::IpAddr::V4;
 use std::net::Ipv4Addr;
@@ -42,6 +51,116 @@ pub async fn control(
     Ok(hex::encode(reply.reply))
 }
 
+// msg is a signed sphinx_key_common::ext::ExtControl, the reply is an ExtResponse
+#[post("/ext_control?<msg>&<cid>")]
+pub async fn ext_control(
+    sender: &State<Sender<ChannelRequest>>,
+    msg: &str,
+    cid: &str,
+) -> Result<String> {
+    let message = hex::decode(msg)?;
+    if message.len() < 65 {
+        return Err(Error::Fail);
+    }
+    let (request, reply_rx) = ChannelRequest::new(cid, common_topics::EXT_CONTROL, message);
+    sender.send(request).await.map_err(|_| Error::Fail)?;
+    let reply = reply_rx.await.map_err(|_| Error::Fail)?;
+    if reply.is_empty() {
+        return Err(Error::Fail);
+    }
+    Ok(String::from_utf8_lossy(&reply.reply).to_string())
+}
+
+// the payments waiting on each signer's owner
+#[get("/approvals")]
+pub async fn get_approvals() -> Result<String> {
+    Ok(serde_json::to_string(&client_approvals())?)
+}
+
+// msg is a signed ext::ExtControl::Decide, the same as POST /ext_control
+#[post("/approvals?<msg>&<cid>")]
+pub async fn decide_approval(
+    sender: &State<Sender<ChannelRequest>>,
+    msg: &str,
+    cid: &str,
+) -> Result<String> {
+    ext_control(sender, msg, cid).await
+}
+
+// msg is a signed OTA control msg with the url set to ota_proto::MQTT_URL,
+// the signer checks it and then the image is pushed in chunks
+#[post("/ota?<msg>&<cid>", data = "<image>")]
+pub async fn ota(
+    sender: &State<Sender<ChannelRequest>>,
+    msg: &str,
+    cid: &str,
+    image: Data<'_>,
+) -> Result<String> {
+    // older signers only take an OTA from a url
+    if !client_has(cid, caps::OTA_MQTT) {
+        return Err(Error::Ota(format!(
+            "{} can't take an update over MQTT",
+            cid
+        )));
+    }
+    let image = image
+        .open((MAX_IMAGE_LEN as u64).bytes())
+        .into_bytes()
+        .await?;
+    if !image.is_complete() {
+        return Err(Error::Ota("image is too big".to_string()));
+    }
+    let image = image.into_inner();
+    // before the signer is armed, it would wait on chunks that never come
+    if image.is_empty() {
+        return Err(Error::Ota("image is empty".to_string()));
+    }
+    let total = image.len() as u32;
+    let message = hex::decode(msg)?;
+    if message.len() < 65 {
+        return Err(Error::Fail);
+    }
+    client_ota_progress(cid, None);
+    let (request, reply_rx) = ChannelRequest::new(cid, topics::CONTROL, message);
+    sender.send(request).await.map_err(|_| Error::Fail)?;
+    let reply = reply_rx.await.map_err(|_| Error::Fail)?;
+    if reply.is_empty() {
+        return Err(Error::Fail);
+    }
+    // only an OtaConfirm means the signer took the update and waits on chunks
+    match deserialize_controlresponse(&mut Bytes::new(&reply.reply)) {
+        Ok(ControlResponse::OtaConfirm(_)) => (),
+        Ok(ControlResponse::Error(e)) => return Err(Error::Ota(e)),
+        Ok(res) => return Err(Error::Ota(format!("not an OTA confirm: {:?}", res))),
+        Err(e) => return Err(Error::Ota(format!("bad CONTROL reply: {:?}", e))),
+    }
+    let mut offset = 0u32;
+    while offset < total {
+        let end = std::cmp::min(offset as usize + CHUNK_LEN, image.len());
+        let mut chunk = ota_proto::chunk_header(offset, total).to_vec();
+        chunk.extend_from_slice(&image[offset as usize..end]);
+        let ack = ChannelRequest::send(cid, common_topics::OTA_CHUNK, chunk, sender)
+            .await
+            .map_err(|_| Error::Fail)?;
+        match ota_proto::parse_ack(&ack) {
+            Some(next) if next > offset => offset = next,
+            _ => {
+                return Err(Error::Ota(format!(
+                    "signer stopped the update at {} of {} bytes",
+                    offset, total
+                )))
+            }
+        }
+    }
+    Ok(hex::encode(reply.reply))
+}
+
+// the last progress the signer reported, for either kind of update
+#[get("/ota?<cid>")]
+pub async fn get_ota(cid: &str) -> Result<String> {
+    Ok(serde_json::to_string(&client_ota_status(cid))?)
+}
+
 #[get("/errors")]
 async fn errors(error_tx: &State<broadcast::Sender<Vec<u8>>>, mut end: Shutdown) -> EventStream![] {
     let mut rx = error_tx.subscribe();
@@ -73,7 +192,19 @@ pub fn launch_rocket(
     };
     rocket::build()
         .configure(config)
-        .mount("/api/", routes![control, errors, get_clients])
+        .mount(
+            "/api/",
+            routes![
+                control,
+                ext_control,
+                ota,
+                get_ota,
+                errors,
+                get_clients,
+                get_approvals,
+                decide_approval
+            ],
+        )
         .attach(CORS)
         .manage(tx)
         .manage(error_tx)
@@ -89,6 +220,8 @@ pub enum Error {
     Hex(#[from] hex::FromHexError),
     #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("ota error: {0}")]
+    Ota(String),
 }
 
 use rocket::http::Status;
@@ -0,0 +1,238 @@
+//! Control messages that `sphinx_glyph::control` doesn't have. They are
+//! JSON, signed with `sphinx_auther::nonce` by the same key and nonce
+//! as the glyph control messages.
+
+use crate::gesture::Gesture;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtControl {
+    QueryOtaKeys,
+    UpdateOtaKeys(OtaKeys),
+    QueryWifi,
+    AddWifi(WifiNetwork),
+    // by ssid
+    RemoveWifi(String),
+    // the seed, sealed to this hex pubkey
+    ExportSeed(String),
+    QueryStore,
+    // wipes, reports back, and restarts
+    Reset(ResetMode),
+    QueryButton,
+    SetButton(ButtonActions),
+    QueryApprovals,
+    // a pending approval by id, yes or no
+    Decide { id: String, approve: bool },
+    QueryBrightness,
+    // of the LED, 1 to 255
+    SetBrightness(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtResponse {
+    OtaKeys(OtaKeys),
+    // without the passwords
+    WifiNetworks(Vec<WifiNetwork>),
+    // chacha20poly1305 encrypted to the ECDH secret between `pubkey` and the
+    // one it was exported to. The fields of provision::SealedSeed::Device
+    SeedBackup { pubkey: String, cipher: String },
+    Store(StoreHealth),
+    // wiped, about to restart. Also sent after the next connect for a reset
+    // from the button
+    Reset(ResetMode),
+    Button(ButtonActions),
+    Approvals(Vec<Approval>),
+    Brightness(u8),
+    Error(String),
+}
+
+// what a reset wipes, in order of how much
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ResetMode {
+    // the config and the saved networks, back to the config server
+    Wifi,
+    // the policy and velocity, back to the defaults
+    Policy,
+    // the signer state on the sd card, restored from LSS on the next boot
+    Store,
+    // all of the above and the seed. The OTA keys, the firmware version
+    // and the control nonce are kept
+    Full,
+}
+
+// a set of release keys. An update must be signed by the current set,
+// over release::rotation_message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OtaKeys {
+    pub sequence: u64,
+    pub threshold: u8,
+    pub addresses: Vec<String>,
+    #[serde(default)]
+    pub sigs: String,
+}
+
+// the most networks saved besides the one from the config server
+pub const MAX_WIFI_NETWORKS: usize = 8;
+
+// a saved wifi network. Those in range are tried by priority, lowest first,
+// and then by signal strength. The network from the config server has priority 0
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub pass: String,
+    #[serde(default)]
+    pub priority: u8,
+}
+
+impl WifiNetwork {
+    // the limits of the esp-idf wifi config, empty for an open network
+    pub fn is_valid(&self) -> bool {
+        (1..=32).contains(&self.ssid.len())
+            && (self.pass.is_empty() || (8..=64).contains(&self.pass.len()))
+    }
+}
+
+// the signer state on the sd card
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreHealth {
+    pub entries: u64,
+    pub bytes: u64,
+    // set aside at boot, as half written, unreadable, or behind LSS
+    pub quarantined: Vec<String>,
+}
+
+// what a gesture on the button does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ButtonAction {
+    Nothing,
+    // the request waiting on the signer
+    Approve,
+    Reject,
+    // the current status again on the LED
+    ShowStatus,
+    Reset(ResetMode),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ButtonActions {
+    pub short: ButtonAction,
+    pub double: ButtonAction,
+    pub long: ButtonAction,
+}
+
+impl Default for ButtonActions {
+    fn default() -> Self {
+        Self {
+            short: ButtonAction::Approve,
+            double: ButtonAction::Reject,
+            long: ButtonAction::ShowStatus,
+        }
+    }
+}
+
+impl ButtonActions {
+    pub fn get(&self, gesture: Gesture) -> ButtonAction {
+        match gesture {
+            Gesture::Short => self.short,
+            Gesture::Double => self.double,
+            Gesture::Long => self.long,
+        }
+    }
+    // a reset only on a long press, so it can't be set off by a bump
+    pub fn is_valid(&self) -> bool {
+        !matches!(self.short, ButtonAction::Reset(_))
+            && !matches!(self.double, ButtonAction::Reset(_))
+    }
+}
+
+// a payment over the policy, held for the owner to approve on the button
+// or with ExtControl::Decide. Published on topics::APPROVAL when it comes in
+// and again when it is decided or expires. Once approved, the node has to
+// try the payment again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Approval {
+    // the payment hash, or the txid for onchain
+    pub id: String,
+    pub kind: ApprovalKind,
+    pub amount_msat: u64,
+    pub state: ApprovalState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApprovalKind {
+    Invoice,
+    Keysend,
+    Onchain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApprovalState {
+    Pending,
+    Approved,
+    Rejected,
+    // not decided in time, the same as rejected
+    Expired,
+}
+
+// why the signer restarted, kept in NVS and published on topics::RESTART
+// after the next connect
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Restart {
+    pub reason: RestartReason,
+    // unix secs, 0 if the time wasn't synced yet
+    pub at: u64,
+    // secs since that boot
+    pub uptime: u64,
+    // counts up from the first boot with this kept
+    pub boot: u32,
+    // the error, or the panic message and where
+    pub context: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestartReason {
+    Disconnected,
+    BadSequence,
+    LssInit,
+    LssConflict,
+    // writing the signer state to the sd card
+    CommitFailed,
+    // put back from LSS
+    StoreRepaired,
+    Wifi,
+    Sntp,
+    Pin,
+    // new config from the config server
+    Config,
+    Ota,
+    Reset,
+    // the heap watchdog, before it ran out in the middle of a commit
+    OutOfMemory,
+    Panic,
+    // the rest are from the chip's reset reason, with nothing recorded by
+    // the firmware. A panic outside of rust is a Panic with no message
+    Watchdog,
+    Brownout,
+}
+
+// published on topics::HEAP when the memory left on the signer changes level
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeapWarning {
+    pub pressure: HeapPressure,
+    pub free: u32,
+    pub largest_block: u32,
+    // the smallest largest block since boot
+    pub lowest_block: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum HeapPressure {
+    Normal,
+    // new VLS msgs wait until there is enough again
+    Soft,
+    // restarts once the LSS commit in flight is done
+    Hard,
+}
@@ -0,0 +1,207 @@
+//! Button gestures from pin samples. There is no clock or GPIO in here, the
+//! caller passes the level and the time in millis, so the timing works the
+//! same on the host as on the signer.
+
+#[cfg(feature = "proto")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "proto", derive(Serialize, Deserialize))]
+pub enum Gesture {
+    Short,
+    Double,
+    // fires while still held, so there is something to see before letting go
+    Long,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    // a level has to hold this long to count
+    pub debounce: u32,
+    // held at least this long
+    pub long: u32,
+    // the most from letting go to the second press of a double
+    pub double_gap: u32,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            debounce: 40,
+            long: 3000,
+            double_gap: 400,
+        }
+    }
+}
+
+// a short press is only known once double_gap passes without a second one
+#[derive(Debug, Clone)]
+pub struct Detector {
+    timing: Timing,
+    // the last sample, and since when
+    raw: bool,
+    raw_since: u32,
+    // after debouncing
+    pressed: bool,
+    down_at: u32,
+    long_fired: bool,
+    // let go of a first press at
+    first_up: Option<u32>,
+}
+
+impl Detector {
+    pub fn new(timing: Timing) -> Self {
+        Self {
+            timing,
+            raw: false,
+            raw_since: 0,
+            pressed: false,
+            down_at: 0,
+            long_fired: false,
+            first_up: None,
+        }
+    }
+
+    // call at a steady rate, with `now` wrapping like a millis counter
+    pub fn update(&mut self, pressed: bool, now: u32) -> Option<Gesture> {
+        if pressed != self.raw {
+            self.raw = pressed;
+            self.raw_since = now;
+        }
+        if self.raw != self.pressed && now.wrapping_sub(self.raw_since) >= self.timing.debounce {
+            self.pressed = self.raw;
+            return self.edge(now);
+        }
+        if self.pressed {
+            if !self.long_fired && now.wrapping_sub(self.down_at) >= self.timing.long {
+                self.long_fired = true;
+                self.first_up = None;
+                return Some(Gesture::Long);
+            }
+        } else if let Some(up) = self.first_up {
+            if now.wrapping_sub(up) > self.timing.double_gap {
+                self.first_up = None;
+                return Some(Gesture::Short);
+            }
+        }
+        None
+    }
+
+    fn edge(&mut self, now: u32) -> Option<Gesture> {
+        if self.pressed {
+            self.down_at = now;
+            self.long_fired = false;
+            return None;
+        }
+        if self.long_fired {
+            return None;
+        }
+        match self.first_up.take() {
+            Some(_) => Some(Gesture::Double),
+            None => {
+                self.first_up = Some(now);
+                None
+            }
+        }
+    }
+}
+
+impl Default for Detector {
+    fn default() -> Self {
+        Self::new(Timing::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    // samples every 10ms, like the button thread, from `at` for `ms`
+    fn hold(d: &mut Detector, pressed: bool, at: &mut u32, ms: u32) -> Vec<Gesture> {
+        let mut fired = Vec::new();
+        for _ in 0..ms / 10 {
+            fired.extend(d.update(pressed, *at));
+            *at = at.wrapping_add(10);
+        }
+        fired
+    }
+
+    #[test]
+    fn bounces_are_ignored() {
+        let mut d = Detector::default();
+        let mut at = 0;
+        assert!(hold(&mut d, false, &mut at, 100).is_empty());
+        assert!(hold(&mut d, true, &mut at, 30).is_empty());
+        assert!(hold(&mut d, false, &mut at, 1000).is_empty());
+    }
+
+    #[test]
+    fn short_once_the_gap_passes() {
+        let mut d = Detector::default();
+        let mut at = 0;
+        hold(&mut d, false, &mut at, 100);
+        assert!(hold(&mut d, true, &mut at, 100).is_empty());
+        // let go at 240 after the debounce, the gap ends past 640
+        assert!(hold(&mut d, false, &mut at, 450).is_empty());
+        assert_eq!(hold(&mut d, false, &mut at, 10), [Gesture::Short]);
+        assert!(hold(&mut d, false, &mut at, 1000).is_empty());
+    }
+
+    #[test]
+    fn double_at_the_end_of_the_gap() {
+        let mut d = Detector::default();
+        let mut at = 0;
+        hold(&mut d, false, &mut at, 100);
+        hold(&mut d, true, &mut at, 100);
+        // pressed again at 640 after the debounce, just the gap after 240
+        assert!(hold(&mut d, false, &mut at, 400).is_empty());
+        assert!(hold(&mut d, true, &mut at, 100).is_empty());
+        assert_eq!(hold(&mut d, false, &mut at, 1000), [Gesture::Double]);
+    }
+
+    #[test]
+    fn two_shorts_just_past_the_gap() {
+        let mut d = Detector::default();
+        let mut at = 0;
+        hold(&mut d, false, &mut at, 100);
+        hold(&mut d, true, &mut at, 100);
+        assert!(hold(&mut d, false, &mut at, 420).is_empty());
+        assert_eq!(hold(&mut d, true, &mut at, 100), [Gesture::Short]);
+        assert_eq!(hold(&mut d, false, &mut at, 1000), [Gesture::Short]);
+    }
+
+    #[test]
+    fn long_fires_while_held() {
+        let mut d = Detector::default();
+        let mut at = 0;
+        // pressed at 40 after the debounce
+        assert!(hold(&mut d, true, &mut at, 3040).is_empty());
+        assert_eq!(hold(&mut d, true, &mut at, 10), [Gesture::Long]);
+        assert!(hold(&mut d, true, &mut at, 2000).is_empty());
+        assert!(hold(&mut d, false, &mut at, 1000).is_empty());
+    }
+
+    #[test]
+    fn nothing_else_after_long() {
+        let mut d = Detector::default();
+        let mut at = 0;
+        hold(&mut d, true, &mut at, 100);
+        hold(&mut d, false, &mut at, 100);
+        // the second press of a double, held into a long
+        assert_eq!(hold(&mut d, true, &mut at, 4000), [Gesture::Long]);
+        assert!(hold(&mut d, false, &mut at, 1000).is_empty());
+    }
+
+    #[test]
+    fn times_wrap_around() {
+        let mut d = Detector::default();
+        let mut at = u32::MAX - 195;
+        hold(&mut d, false, &mut at, 100);
+        hold(&mut d, true, &mut at, 100);
+        assert_eq!(hold(&mut d, false, &mut at, 1000), [Gesture::Short]);
+        assert_eq!(hold(&mut d, true, &mut at, 3500), [Gesture::Long]);
+        assert!(hold(&mut d, false, &mut at, 1000).is_empty());
+    }
+}
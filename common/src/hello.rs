@@ -0,0 +1,136 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+// bump when the messages between signer and broker change incompatibly
+pub const PROTOCOL_VERSION: u16 = 1;
+// the oldest peer version this side can still talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 0;
+
+// capabilities advertised in the HELLO
+pub mod caps {
+    pub const LSS: &str = "lss";
+    pub const QOS1: &str = "qos1";
+    pub const TELEMETRY: &str = "telemetry";
+    pub const OTA_MQTT: &str = "ota_mqtt";
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    // kept as strings so older peers can skip unknown capabilities
+    pub caps: Vec<String>,
+    #[serde(default)]
+    pub firmware: Option<String>,
+    // the signed OTA version the signer is running, if it was ever updated
+    #[serde(default)]
+    pub ota_version: Option<u64>,
+}
+
+impl Hello {
+    pub fn new(caps: &[&str], firmware: Option<&str>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            caps: caps.iter().map(|c| c.to_string()).collect(),
+            firmware: firmware.map(|f| f.to_string()),
+            ota_version: None,
+        }
+    }
+    pub fn with_ota_version(mut self, version: Option<u64>) -> Self {
+        self.ota_version = version;
+        self
+    }
+    // signers from before the negotiation send an empty HELLO
+    pub fn legacy() -> Self {
+        Self {
+            version: 0,
+            min_version: 0,
+            caps: [caps::LSS].iter().map(|c| c.to_string()).collect(),
+            firmware: None,
+            ota_version: None,
+        }
+    }
+    pub fn has(&self, cap: &str) -> bool {
+        self.caps.iter().any(|c| c == cap)
+    }
+    // returns the protocol version both sides will speak
+    pub fn negotiate(&self, ours: &Hello, required: &[&str]) -> Result<u16, String> {
+        if self.version < ours.min_version {
+            return Err(format!(
+                "peer protocol v{} is too old, v{} or newer is required",
+                self.version, ours.min_version
+            ));
+        }
+        if ours.version < self.min_version {
+            return Err(format!(
+                "peer requires protocol v{} or newer, this side speaks v{}",
+                self.min_version, ours.version
+            ));
+        }
+        let missing: Vec<&str> = required.iter().filter(|c| !self.has(c)).copied().collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "peer is missing capabilities: {}",
+                missing.join(", ")
+            ));
+        }
+        Ok(self.version.min(ours.version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(version: u16, min_version: u16, caps: &[&str]) -> Hello {
+        Hello {
+            version,
+            min_version,
+            ..Hello::new(caps, None)
+        }
+    }
+
+    #[test]
+    fn speaks_the_lower_version() {
+        let ours = peer(3, 1, &[caps::LSS]);
+        assert_eq!(peer(2, 0, &[caps::LSS]).negotiate(&ours, &[]), Ok(2));
+        assert_eq!(peer(5, 2, &[caps::LSS]).negotiate(&ours, &[]), Ok(3));
+    }
+
+    #[test]
+    fn refuses_a_peer_too_old_for_us() {
+        let ours = peer(3, 2, &[]);
+        assert!(peer(1, 0, &[]).negotiate(&ours, &[]).is_err());
+    }
+
+    #[test]
+    fn refuses_a_peer_that_needs_newer() {
+        let ours = peer(3, 0, &[]);
+        assert!(peer(5, 4, &[]).negotiate(&ours, &[]).is_err());
+    }
+
+    #[test]
+    fn requires_capabilities() {
+        let ours = Hello::new(&[caps::LSS, caps::OTA_MQTT], None);
+        let theirs = Hello::new(&[caps::QOS1, caps::OTA_MQTT, "unknown"], None);
+        assert_eq!(
+            theirs.negotiate(&ours, &[caps::OTA_MQTT]),
+            Ok(PROTOCOL_VERSION)
+        );
+        let err = theirs
+            .negotiate(&ours, &[caps::LSS, caps::TELEMETRY])
+            .unwrap_err();
+        assert!(err.contains(caps::LSS) && err.contains(caps::TELEMETRY));
+    }
+
+    #[test]
+    fn legacy_has_only_lss() {
+        let legacy = Hello::legacy();
+        let ours = Hello::new(&[], None);
+        assert_eq!(legacy.negotiate(&ours, &[caps::LSS]), Ok(0));
+        assert!(legacy.negotiate(&ours, &[caps::OTA_MQTT]).is_err());
+    }
+}
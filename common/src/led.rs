@@ -0,0 +1,374 @@
+//! LED patterns, shared by the signer and the factory app. A pattern is
+//! written as a few steps, like `"red:200 off:200 *3"`: a colour (a name or
+//! `#rrggbb`) or `off`, lit for that many millis, and an optional repeat
+//! count at the end, forever without one. No alloc and no clock, the caller
+//! plays the steps `Player::step` hands back.
+
+use core::fmt;
+
+// steps in one pattern, enough for the longest error code
+pub const MAX_STEPS: usize = 16;
+// for a new signer, the LED is blinding at full
+pub const DEFAULT_BRIGHTNESS: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+    pub fn scaled(self, brightness: u8) -> Self {
+        let scale = |c: u8| ((c as u16 * (brightness as u16 + 1)) >> 8) as u8;
+        Self::new(scale(self.r), scale(self.g), scale(self.b))
+    }
+    // the order the neopixel takes the bits in
+    pub fn grb(self) -> u32 {
+        ((self.g as u32) << 16) | ((self.r as u32) << 8) | self.b as u32
+    }
+    fn parse(s: &str) -> Option<Self> {
+        if let Some(hex) = s.strip_prefix('#') {
+            let n = u32::from_str_radix(hex, 16)
+                .ok()
+                .filter(|_| hex.len() == 6)?;
+            return Some(Self::new((n >> 16) as u8, (n >> 8) as u8, n as u8));
+        }
+        NAMED.iter().find(|(name, _)| *name == s).map(|(_, c)| *c)
+    }
+}
+
+pub const OFF: Rgb = Rgb::new(0, 0, 0);
+pub const RED: Rgb = Rgb::new(255, 0, 0);
+pub const GREEN: Rgb = Rgb::new(0, 255, 0);
+pub const BLUE: Rgb = Rgb::new(0, 0, 255);
+pub const YELLOW: Rgb = Rgb::new(255, 255, 0);
+pub const CYAN: Rgb = Rgb::new(0, 255, 255);
+pub const PURPLE: Rgb = Rgb::new(128, 0, 255);
+pub const MAGENTA: Rgb = Rgb::new(255, 0, 255);
+pub const ORANGE: Rgb = Rgb::new(255, 55, 0);
+pub const WHITE: Rgb = Rgb::new(255, 255, 255);
+
+const NAMED: [(&str, Rgb); 10] = [
+    ("off", OFF),
+    ("red", RED),
+    ("green", GREEN),
+    ("blue", BLUE),
+    ("yellow", YELLOW),
+    ("cyan", CYAN),
+    ("purple", PURPLE),
+    ("magenta", MAGENTA),
+    ("orange", ORANGE),
+    ("white", WHITE),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Step {
+    pub color: Rgb,
+    pub millis: u16,
+}
+
+// a higher one plays over a lower one until its repeats run out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Status,
+    Notice,
+    Error,
+}
+
+const PRIORITIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pattern {
+    steps: [Step; MAX_STEPS],
+    len: usize,
+    // 0 for forever
+    pub repeat: u8,
+    pub priority: Priority,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError<'a>(pub &'a str);
+
+impl fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad LED pattern step {:?}", self.0)
+    }
+}
+
+impl Pattern {
+    pub fn parse(s: &str, priority: Priority) -> Result<Self, ParseError<'_>> {
+        let mut p = Self {
+            steps: [Step::default(); MAX_STEPS],
+            len: 0,
+            repeat: 0,
+            priority,
+        };
+        for token in s.split_whitespace() {
+            if let Some(n) = token.strip_prefix('*') {
+                p.repeat = n.parse().map_err(|_| ParseError(token))?;
+                continue;
+            }
+            let (color, millis) = token.split_once(':').ok_or(ParseError(token))?;
+            let step = Step {
+                color: Rgb::parse(color).ok_or(ParseError(token))?,
+                millis: millis.parse().map_err(|_| ParseError(token))?,
+            };
+            p.push(step).ok_or(ParseError(token))?;
+        }
+        if p.len == 0 {
+            return Err(ParseError(s));
+        }
+        Ok(p)
+    }
+
+    // only for patterns that are known to parse, like the built in ones
+    pub fn must(s: &str, priority: Priority) -> Self {
+        Self::parse(s, priority).expect("bad built in LED pattern")
+    }
+
+    // one colour on and off
+    pub fn blink(color: Rgb, on: u16, off: u16, repeat: u8, priority: Priority) -> Self {
+        let mut p = Self {
+            steps: [Step::default(); MAX_STEPS],
+            len: 2,
+            repeat,
+            priority,
+        };
+        p.steps[0] = Step { color, millis: on };
+        p.steps[1] = Step {
+            color: OFF,
+            millis: off,
+        };
+        p
+    }
+
+    // the blink code for an error, `code` red blinks and a pause, three times
+    pub fn error(code: ErrorCode) -> Self {
+        let mut p = Self {
+            steps: [Step::default(); MAX_STEPS],
+            len: 0,
+            repeat: 3,
+            priority: Priority::Error,
+        };
+        for _ in 0..code.blinks() {
+            let _ = p.push(Step {
+                color: RED,
+                millis: 250,
+            });
+            let _ = p.push(Step {
+                color: OFF,
+                millis: 250,
+            });
+        }
+        let _ = p.push(Step {
+            color: OFF,
+            millis: 1200,
+        });
+        p
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps[..self.len]
+    }
+
+    // once through, without the repeats
+    pub fn millis(&self) -> u32 {
+        self.steps().iter().map(|s| s.millis as u32).sum()
+    }
+
+    fn push(&mut self, step: Step) -> Option<()> {
+        *self.steps.get_mut(self.len)? = step;
+        self.len += 1;
+        Some(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorCode {
+    SdCard,
+    Wifi,
+    Broker,
+    Lss,
+    Ota,
+    Memory,
+}
+
+impl ErrorCode {
+    // one blink is too easy to miss, so the codes start at two
+    pub fn blinks(self) -> u8 {
+        self as u8 + 2
+    }
+}
+
+// one pattern for each priority, the highest one plays
+#[derive(Debug, Clone)]
+pub struct Player {
+    layers: [Option<Layer>; PRIORITIES],
+    brightness: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Layer {
+    pattern: Pattern,
+    step: usize,
+    played: u8,
+}
+
+impl Player {
+    pub fn new(brightness: u8) -> Self {
+        Self {
+            layers: [None; PRIORITIES],
+            brightness,
+        }
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+
+    // replaces whatever was playing at its priority
+    pub fn play(&mut self, pattern: Pattern) {
+        self.layers[pattern.priority as usize] = Some(Layer {
+            pattern,
+            step: 0,
+            played: 0,
+        });
+    }
+
+    pub fn clear(&mut self, priority: Priority) {
+        self.layers[priority as usize] = None;
+    }
+
+    // the colour to show and for how long, off when nothing is playing
+    pub fn step(&mut self) -> Step {
+        let brightness = self.brightness;
+        let Some(slot) = self.layers.iter_mut().rev().find(|l| l.is_some()) else {
+            return Step {
+                color: OFF,
+                millis: 100,
+            };
+        };
+        let layer = slot.as_mut().unwrap();
+        let steps = layer.pattern.steps();
+        let step = steps[layer.step];
+        layer.step += 1;
+        if layer.step >= steps.len() {
+            layer.step = 0;
+            layer.played = layer.played.saturating_add(1);
+            if layer.pattern.repeat != 0 && layer.played >= layer.pattern.repeat {
+                *slot = None;
+            }
+        }
+        Step {
+            color: step.color.scaled(brightness),
+            millis: step.millis,
+        }
+    }
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self::new(DEFAULT_BRIGHTNESS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_steps_and_repeat() {
+        let p = Pattern::parse("red:200 #00ff80:50 off:200 *3", Priority::Notice).unwrap();
+        assert_eq!(p.repeat, 3);
+        assert_eq!(p.priority, Priority::Notice);
+        assert_eq!(p.steps().len(), 3);
+        assert_eq!(p.steps()[1].color, Rgb::new(0, 255, 128));
+        assert_eq!(p.millis(), 450);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let parse = |s| Pattern::parse(s, Priority::Status);
+        assert_eq!(parse("pink:100"), Err(ParseError("pink:100")));
+        assert_eq!(parse("#12345:100"), Err(ParseError("#12345:100")));
+        assert_eq!(parse("#gggggg:100"), Err(ParseError("#gggggg:100")));
+        assert_eq!(parse("red"), Err(ParseError("red")));
+        assert_eq!(parse("red:long"), Err(ParseError("red:long")));
+        assert_eq!(parse("red:100 *x"), Err(ParseError("*x")));
+        assert_eq!(parse(""), Err(ParseError("")));
+        assert_eq!(parse(" *2 "), Err(ParseError(" *2 ")));
+    }
+
+    #[test]
+    fn too_many_steps() {
+        let mut s = [0u8; 6 * (MAX_STEPS + 1)];
+        for step in s.chunks_mut(6) {
+            step.copy_from_slice(b"off:1 ");
+        }
+        let s = core::str::from_utf8(&s).unwrap();
+        assert!(Pattern::parse(&s[..6 * MAX_STEPS], Priority::Status).is_ok());
+        assert_eq!(
+            Pattern::parse(s, Priority::Status),
+            Err(ParseError("off:1"))
+        );
+    }
+
+    #[test]
+    fn error_codes_blink() {
+        let p = Pattern::error(ErrorCode::Memory);
+        let reds = p.steps().iter().filter(|s| s.color == RED).count();
+        assert_eq!(reds, ErrorCode::Memory.blinks() as usize);
+        assert!(p.steps().len() <= MAX_STEPS);
+        assert_eq!(p.priority, Priority::Error);
+    }
+
+    #[test]
+    fn higher_priority_plays_until_its_repeats_run_out() {
+        let mut player = Player::new(255);
+        player.play(Pattern::must("green:100", Priority::Status));
+        player.play(Pattern::blink(RED, 10, 20, 2, Priority::Error));
+        let colors = [RED, OFF, RED, OFF, GREEN, GREEN];
+        for color in colors {
+            assert_eq!(player.step().color, color);
+        }
+    }
+
+    #[test]
+    fn clear_falls_back_to_the_next_priority() {
+        let mut player = Player::new(255);
+        player.play(Pattern::must("blue:100", Priority::Status));
+        player.play(Pattern::must("white:100", Priority::Notice));
+        assert_eq!(player.step().color, WHITE);
+        player.clear(Priority::Notice);
+        assert_eq!(player.step().color, BLUE);
+        player.clear(Priority::Status);
+        assert_eq!(
+            player.step(),
+            Step {
+                color: OFF,
+                millis: 100
+            }
+        );
+    }
+
+    #[test]
+    fn brightness_scales() {
+        assert_eq!(WHITE.scaled(255), WHITE);
+        assert_eq!(WHITE.scaled(0), Rgb::new(0, 0, 0));
+        assert_eq!(Rgb::new(255, 128, 2).scaled(127), Rgb::new(127, 64, 1));
+        let mut player = Player::new(63);
+        player.play(Pattern::must("#ff8000:100", Priority::Status));
+        assert_eq!(player.step().color, Rgb::new(63, 32, 0));
+        player.set_brightness(255);
+        assert_eq!(player.step().color, Rgb::new(255, 128, 0));
+    }
+
+    #[test]
+    fn grb_order() {
+        assert_eq!(Rgb::new(1, 2, 4).grb(), 0x02_01_04);
+    }
+}
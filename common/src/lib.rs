@@ -0,0 +1,23 @@
+//! Shared between the signer firmware, the factory app, the broker and the tester.
+//! Everything here must build for `no_std`, the factory app has no allocator.
+#![no_std]
+
+#[cfg(feature = "proto")]
+extern crate alloc;
+
+#[cfg(feature = "proto")]
+pub mod ext;
+pub mod gesture;
+#[cfg(feature = "proto")]
+pub mod hello;
+pub mod led;
+#[cfg(feature = "mnemonic")]
+pub mod mnemonic;
+pub mod ota;
+#[cfg(feature = "provision")]
+pub mod provision;
+#[cfg(feature = "verify")]
+pub mod release;
+#[cfg(feature = "proto")]
+pub mod setup;
+pub mod topics;
@@ -0,0 +1,38 @@
+//! BIP39 mnemonics for the signer's 32 byte seed. The seed is the first half
+//! of the 64 byte BIP39 seed, so a passphrase can be added on the host side.
+
+pub use bip39::{Error, Mnemonic};
+
+// what the signer generates, from 32 bytes of entropy
+pub const WORD_COUNT: usize = 24;
+pub const ENTROPY_LEN: usize = 32;
+// words asked back before a generated seed is kept
+pub const CHALLENGE_LEN: usize = 3;
+
+pub fn seed_from_mnemonic(phrase: &str, passphrase: &str) -> Result<[u8; 32], Error> {
+    let mnemonic = Mnemonic::parse(phrase)?;
+    Ok(seed(&mnemonic, passphrase))
+}
+
+pub fn seed(mnemonic: &Mnemonic, passphrase: &str) -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    seed.copy_from_slice(&mnemonic.to_seed(passphrase)[..32]);
+    seed
+}
+
+pub fn generate(entropy: &[u8; ENTROPY_LEN]) -> Mnemonic {
+    Mnemonic::from_entropy(entropy).expect("32 bytes is a valid entropy length")
+}
+
+// CHALLENGE_LEN distinct word positions, picked with the random bytes
+pub fn challenge(random: &[u8; CHALLENGE_LEN]) -> [usize; CHALLENGE_LEN] {
+    let mut picked = [0usize; CHALLENGE_LEN];
+    for i in 0..CHALLENGE_LEN {
+        let mut pos = random[i] as usize % WORD_COUNT;
+        while picked[..i].contains(&pos) {
+            pos = (pos + 1) % WORD_COUNT;
+        }
+        picked[i] = pos;
+    }
+    picked
+}
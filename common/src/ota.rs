@@ -0,0 +1,130 @@
+//! Framing for firmware images streamed over MQTT.
+
+use core::fmt;
+#[cfg(feature = "proto")]
+use serde::{Deserialize, Serialize};
+
+// put in OtaParams.url to have the image pushed by the broker
+pub const MQTT_URL: &str = "mqtt";
+// small enough for the signer's MQTT buffers and the sdcard writes
+pub const CHUNK_LEN: usize = 4096;
+pub const CHUNK_HEADER_LEN: usize = 8;
+// the size of the ota_0 partition in factory/table.csv
+pub const MAX_IMAGE_LEN: u32 = 0x3b0000;
+
+// offset (4 bytes) | total (4 bytes) | data
+pub fn chunk_header(offset: u32, total: u32) -> [u8; CHUNK_HEADER_LEN] {
+    let mut h = [0u8; CHUNK_HEADER_LEN];
+    h[..4].copy_from_slice(&offset.to_be_bytes());
+    h[4..].copy_from_slice(&total.to_be_bytes());
+    h
+}
+
+// returns (offset, total, data)
+pub fn parse_chunk(chunk: &[u8]) -> Option<(u32, u32, &[u8])> {
+    if chunk.len() < CHUNK_HEADER_LEN {
+        return None;
+    }
+    let offset = u32::from_be_bytes(chunk[..4].try_into().ok()?);
+    let total = u32::from_be_bytes(chunk[4..8].try_into().ok()?);
+    Some((offset, total, &chunk[CHUNK_HEADER_LEN..]))
+}
+
+// the ack is the next offset the signer expects
+pub fn ack(next: u32) -> [u8; 4] {
+    next.to_be_bytes()
+}
+
+pub fn parse_ack(ack: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(ack.try_into().ok()?))
+}
+
+// the signed OTA params, left next to update.bin for the factory app:
+// version=<version>
+// sha256=<sha256 hex>
+// sigs=<base64 signatures separated by spaces>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Manifest<'a> {
+    pub version: u64,
+    pub sha256: &'a str,
+    pub sigs: &'a str,
+}
+
+impl<'a> Manifest<'a> {
+    pub const MAX_LEN: usize = 1024;
+    pub fn parse(s: &'a str) -> Option<Self> {
+        let (mut version, mut sha256, mut sigs) = (None, None, None);
+        for line in s.lines() {
+            match line.trim().split_once('=') {
+                Some(("version", v)) => version = v.parse().ok(),
+                Some(("sha256", h)) => sha256 = Some(h),
+                Some(("sigs", s)) => sigs = Some(s),
+                _ => (),
+            }
+        }
+        Some(Self {
+            version: version?,
+            sha256: sha256?,
+            sigs: sigs?,
+        })
+    }
+    pub fn write(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(w, "version={}", self.version)?;
+        writeln!(w, "sha256={}", self.sha256)?;
+        writeln!(w, "sigs={}", self.sigs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "proto", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "proto", serde(rename_all = "snake_case"))]
+pub enum Stage {
+    Receiving,
+    Verifying,
+    Installing,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "proto", derive(Serialize, Deserialize))]
+pub struct Progress {
+    pub stage: Stage,
+    pub received: u32,
+    pub total: u32,
+}
+
+impl Progress {
+    pub const LEN: usize = 9;
+    pub fn new(stage: Stage, received: u32, total: u32) -> Self {
+        Self {
+            stage,
+            received,
+            total,
+        }
+    }
+    // stage (1 byte) | received (4 bytes) | total (4 bytes)
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut b = [0u8; Self::LEN];
+        b[0] = self.stage as u8;
+        b[1..5].copy_from_slice(&self.received.to_be_bytes());
+        b[5..].copy_from_slice(&self.total.to_be_bytes());
+        b
+    }
+    pub fn from_slice(b: &[u8]) -> Option<Self> {
+        if b.len() != Self::LEN {
+            return None;
+        }
+        let stage = match b[0] {
+            0 => Stage::Receiving,
+            1 => Stage::Verifying,
+            2 => Stage::Installing,
+            3 => Stage::Failed,
+            _ => return None,
+        };
+        Some(Self {
+            stage,
+            received: u32::from_be_bytes(b[1..5].try_into().ok()?),
+            total: u32::from_be_bytes(b[5..].try_into().ok()?),
+        })
+    }
+}
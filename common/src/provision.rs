@@ -0,0 +1,30 @@
+//! The provisioning file a fleet operator drops on the sdcard. The signer
+//! imports it at boot, in place of the config server, and then wipes it.
+
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+pub const FILE_NAME: &str = "provision.json";
+// the hex pubkey of the device key, written by the signer at boot
+pub const DEVICE_PUB_FILE_NAME: &str = "device.pub";
+// a seed backup from the signer, a SealedSeed::Device sealed to the backup key
+pub const BACKUP_FILE_NAME: &str = "backup.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provision {
+    pub broker: String,
+    pub ssid: String,
+    pub pass: String,
+    pub network: String,
+    // can be left out for a signer that already has a seed
+    #[serde(default)]
+    pub seed: Option<SealedSeed>,
+}
+
+// the seed, chacha20poly1305 encrypted as in sphinx_crypter::chacha
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SealedSeed {
+    // to the ECDH secret between `pubkey` and the device key
+    Device { pubkey: String, cipher: String },
+}
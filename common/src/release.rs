@@ -0,0 +1,481 @@
+//! Release signing keys, and m-of-n verification of signed updates.
+//! Signatures are bitcoin signed messages, like `bitcoin-cli signmessage` makes.
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use core::fmt::{self, Write};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+// signed along with the image, so it can't be installed on different hardware
+pub const CHIP: &str = "esp32c3";
+pub const DOWNGRADE_PREFIX: &str = "downgrade";
+pub const ROTATION_PREFIX: &str = "otakeys";
+
+// P2PKH addresses of the release keys, until a rotation is stored
+pub const RELEASE_KEYS: &[&str] = &["1K51sSTyoVxHhKFtwWpzMZsoHvLshtw3Dp"];
+pub const RELEASE_THRESHOLD: u8 = 1;
+
+pub const MAX_KEYS: usize = 8;
+pub const KEYSET_LEN: usize = 10 + MAX_KEYS * 20;
+
+const MSG_LEN: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    BadAddress,
+    BadThreshold,
+    TooManyKeys,
+    DuplicateKey,
+    StaleSequence,
+    MessageTooLong,
+    NotEnoughSignatures { got: u8, need: u8 },
+    Downgrade { min_version: u64 },
+    BadManifest,
+    HashMismatch,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadAddress => write!(f, "not a P2PKH address"),
+            Self::BadThreshold => write!(f, "threshold must be between 1 and the number of keys"),
+            Self::TooManyKeys => write!(f, "at most {} keys", MAX_KEYS),
+            Self::DuplicateKey => write!(f, "the same key is listed twice"),
+            Self::StaleSequence => write!(f, "key set sequence must increase"),
+            Self::MessageTooLong => write!(f, "signed message is too long"),
+            Self::NotEnoughSignatures { got, need } => {
+                write!(f, "{} valid signatures, {} required", got, need)
+            }
+            Self::Downgrade { min_version } => write!(
+                f,
+                "below the minimum version {}, a signed downgrade is required",
+                min_version
+            ),
+            Self::BadManifest => write!(f, "unreadable manifest"),
+            Self::HashMismatch => write!(f, "image does not match its sha256"),
+        }
+    }
+}
+
+// hash160 of each release key, and how many of them must sign
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySet {
+    sequence: u64,
+    threshold: u8,
+    len: u8,
+    keys: [[u8; 20]; MAX_KEYS],
+}
+
+impl KeySet {
+    pub fn new(sequence: u64, threshold: u8, addresses: &[&str]) -> Result<Self, VerifyError> {
+        if addresses.len() > MAX_KEYS {
+            return Err(VerifyError::TooManyKeys);
+        }
+        if threshold == 0 || threshold as usize > addresses.len() {
+            return Err(VerifyError::BadThreshold);
+        }
+        let mut keys = [[0u8; 20]; MAX_KEYS];
+        for (i, a) in addresses.iter().enumerate() {
+            let h = decode_address(a).ok_or(VerifyError::BadAddress)?;
+            if keys[..i].contains(&h) {
+                return Err(VerifyError::DuplicateKey);
+            }
+            keys[i] = h;
+        }
+        Ok(Self {
+            sequence,
+            threshold,
+            len: addresses.len() as u8,
+            keys,
+        })
+    }
+    pub fn compiled() -> Self {
+        Self::new(0, RELEASE_THRESHOLD, RELEASE_KEYS).expect("bad compiled release keys")
+    }
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn address<'a>(&self, i: usize, buf: &'a mut [u8; 40]) -> Option<&'a str> {
+        let h = self.keys[..self.len()].get(i)?;
+        let n = bs58::encode(h)
+            .with_check_version(0)
+            .onto(&mut buf[..])
+            .ok()?;
+        core::str::from_utf8(&buf[..n]).ok()
+    }
+    // sequence (8 bytes) | threshold (1 byte) | n (1 byte) | n * hash160 (20 bytes)
+    pub fn to_bytes(&self) -> ([u8; KEYSET_LEN], usize) {
+        let mut b = [0u8; KEYSET_LEN];
+        b[..8].copy_from_slice(&self.sequence.to_be_bytes());
+        b[8] = self.threshold;
+        b[9] = self.len;
+        for (i, k) in self.keys[..self.len()].iter().enumerate() {
+            b[10 + i * 20..30 + i * 20].copy_from_slice(k);
+        }
+        (b, 10 + self.len() * 20)
+    }
+    pub fn from_slice(b: &[u8]) -> Option<Self> {
+        if b.len() < 10 {
+            return None;
+        }
+        let len = b[9] as usize;
+        let threshold = b[8];
+        if len > MAX_KEYS || b.len() != 10 + len * 20 || threshold == 0 || threshold as usize > len
+        {
+            return None;
+        }
+        let mut keys = [[0u8; 20]; MAX_KEYS];
+        for (i, k) in keys[..len].iter_mut().enumerate() {
+            k.copy_from_slice(&b[10 + i * 20..30 + i * 20]);
+        }
+        Some(Self {
+            sequence: u64::from_be_bytes(b[..8].try_into().ok()?),
+            threshold,
+            len: len as u8,
+            keys,
+        })
+    }
+    // sigs are base64 signatures separated by whitespace. Ones that
+    // don't verify against msg are skipped, each key counts once
+    pub fn verify(&self, msg: &str, sigs: &str) -> Result<(), VerifyError> {
+        let hash = signed_msg_hash(msg);
+        let mut signed = [false; MAX_KEYS];
+        for sig in sigs.split_whitespace() {
+            let Some(h) = recover_hash160(&hash, sig) else {
+                continue;
+            };
+            if let Some(i) = self.keys[..self.len()].iter().position(|k| *k == h) {
+                signed[i] = true;
+            }
+        }
+        let got = signed.iter().filter(|s| **s).count() as u8;
+        if got < self.threshold {
+            return Err(VerifyError::NotEnoughSignatures {
+                got,
+                need: self.threshold,
+            });
+        }
+        Ok(())
+    }
+    // the next key set is signed by this one, see rotation_message
+    pub fn verify_rotation(&self, next: &KeySet, msg: &str, sigs: &str) -> Result<(), VerifyError> {
+        if next.sequence <= self.sequence {
+            return Err(VerifyError::StaleSequence);
+        }
+        self.verify(msg, sigs)
+    }
+}
+
+// a signed message, formatted without an allocator
+pub struct MsgBuf {
+    buf: [u8; MSG_LEN],
+    len: usize,
+}
+
+impl MsgBuf {
+    fn new() -> Self {
+        Self {
+            buf: [0u8; MSG_LEN],
+            len: 0,
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        // only ever written from &str
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for MsgBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > MSG_LEN {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// a threshold of the keys sign release_message. Below min_version
+// they must also sign downgrade_message, in the same sigs
+pub fn verify_release(
+    keys: &KeySet,
+    version: u64,
+    hash: &str,
+    sigs: &str,
+    min_version: u64,
+) -> Result<(), VerifyError> {
+    keys.verify(release_message(version, hash)?.as_str(), sigs)?;
+    if version >= min_version {
+        return Ok(());
+    }
+    keys.verify(downgrade_message(version, hash)?.as_str(), sigs)
+        .map_err(|_| VerifyError::Downgrade { min_version })
+}
+
+// sha256 of an image, as it is read
+#[derive(Default)]
+pub struct ImageHash(Sha256);
+
+impl ImageHash {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+    pub fn check(self, hex: &str) -> Result<(), VerifyError> {
+        let hash: [u8; 32] = self.0.finalize().into();
+        let mut expected = [0u8; 32];
+        decode_hex(hex, &mut expected).ok_or(VerifyError::HashMismatch)?;
+        if hash == expected {
+            Ok(())
+        } else {
+            Err(VerifyError::HashMismatch)
+        }
+    }
+}
+
+fn decode_hex(hex: &str, out: &mut [u8; 32]) -> Option<()> {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let s = core::str::from_utf8(pair).ok()?;
+        out[i] = u8::from_str_radix(s, 16).ok()?;
+    }
+    Some(())
+}
+
+// "<chip>:<version>:<sha256 hex>"
+pub fn release_message(version: u64, hash: &str) -> Result<MsgBuf, VerifyError> {
+    let mut m = MsgBuf::new();
+    write!(m, "{}:{}:{}", CHIP, version, hash).map_err(|_| VerifyError::MessageTooLong)?;
+    Ok(m)
+}
+
+// "downgrade:<chip>:<version>:<sha256 hex>"
+pub fn downgrade_message(version: u64, hash: &str) -> Result<MsgBuf, VerifyError> {
+    let mut m = MsgBuf::new();
+    write!(m, "{}:{}:{}:{}", DOWNGRADE_PREFIX, CHIP, version, hash)
+        .map_err(|_| VerifyError::MessageTooLong)?;
+    Ok(m)
+}
+
+// "otakeys:<sequence>:<threshold>:<address>,<address>,..."
+pub fn rotation_message(
+    sequence: u64,
+    threshold: u8,
+    addresses: &[&str],
+) -> Result<MsgBuf, VerifyError> {
+    let mut m = MsgBuf::new();
+    let too_long = |_| VerifyError::MessageTooLong;
+    write!(m, "{}:{}:{}:", ROTATION_PREFIX, sequence, threshold).map_err(too_long)?;
+    for (i, a) in addresses.iter().enumerate() {
+        if i > 0 {
+            m.write_str(",").map_err(too_long)?;
+        }
+        m.write_str(a).map_err(too_long)?;
+    }
+    Ok(m)
+}
+
+fn signed_msg_hash(msg: &str) -> [u8; 32] {
+    let mut engine = Sha256::new();
+    engine.update(b"\x18Bitcoin Signed Message:\n");
+    let len = msg.len();
+    if len < 0xfd {
+        engine.update([len as u8]);
+    } else if len <= 0xffff {
+        engine.update([0xfd]);
+        engine.update((len as u16).to_le_bytes());
+    } else {
+        engine.update([0xfe]);
+        engine.update((len as u32).to_le_bytes());
+    }
+    engine.update(msg.as_bytes());
+    Sha256::digest(engine.finalize()).into()
+}
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(data)).into()
+}
+
+fn decode_address(address: &str) -> Option<[u8; 20]> {
+    let mut buf = [0u8; 25];
+    let n = bs58::decode(address)
+        .with_check(Some(0))
+        .onto(&mut buf[..])
+        .ok()?;
+    if n != 21 {
+        return None;
+    }
+    buf[1..21].try_into().ok()
+}
+
+// returns the hash160 of the key that made a 65 byte compact signature
+fn recover_hash160(hash: &[u8; 32], sig: &str) -> Option<[u8; 20]> {
+    let mut raw = [0u8; 66];
+    if STANDARD.decode_slice(sig, &mut raw).ok()? != 65 {
+        return None;
+    }
+    // 27-30 for uncompressed keys, 31-34 for compressed
+    let header = raw[0].checked_sub(27)?;
+    if header > 7 {
+        return None;
+    }
+    let compressed = header >= 4;
+    let mut signature = Signature::from_slice(&raw[1..65]).ok()?;
+    let mut recid = RecoveryId::from_byte(header & 3)?;
+    if let Some(low) = signature.normalize_s() {
+        // negating s flips the parity of the recovered point
+        signature = low;
+        recid = RecoveryId::new(!recid.is_y_odd(), recid.is_x_reduced());
+    }
+    let key = VerifyingKey::recover_from_prehash(hash, &signature, recid).ok()?;
+    Some(hash160(key.to_encoded_point(compressed).as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use k256::ecdsa::SigningKey;
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn key(n: u8) -> SigningKey {
+        SigningKey::from_bytes(&[n; 32].into()).unwrap()
+    }
+
+    fn address(key: &SigningKey) -> String {
+        let pubkey = key.verifying_key().to_encoded_point(true);
+        let mut buf = [0u8; 40];
+        let n = bs58::encode(hash160(pubkey.as_bytes()))
+            .with_check_version(0)
+            .onto(&mut buf[..])
+            .unwrap();
+        String::from(core::str::from_utf8(&buf[..n]).unwrap())
+    }
+
+    // as signmessage makes it, for a compressed key
+    fn sign(key: &SigningKey, msg: &str) -> String {
+        let (sig, recid) = key.sign_prehash_recoverable(&signed_msg_hash(msg)).unwrap();
+        let mut raw = [0u8; 65];
+        raw[0] = 31 + recid.to_byte();
+        raw[1..].copy_from_slice(&sig.to_bytes());
+        let mut out = [0u8; 88];
+        let n = STANDARD.encode_slice(raw, &mut out).unwrap();
+        String::from(core::str::from_utf8(&out[..n]).unwrap())
+    }
+
+    fn sigs(keys: &[SigningKey], msg: &str) -> String {
+        let sigs: Vec<String> = keys.iter().map(|k| sign(k, msg)).collect();
+        sigs.join(" ")
+    }
+
+    fn key_set(sequence: u64, threshold: u8, keys: &[SigningKey]) -> KeySet {
+        let addresses: Vec<String> = keys.iter().map(address).collect();
+        let addresses: Vec<&str> = addresses.iter().map(|a| a.as_str()).collect();
+        KeySet::new(sequence, threshold, &addresses).unwrap()
+    }
+
+    const HASH: &str = "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456";
+
+    #[test]
+    fn threshold_met() {
+        let keys = [key(1), key(2), key(3)];
+        let set = key_set(0, 2, &keys);
+        let msg = release_message(5, HASH).unwrap();
+        assert_eq!(
+            set.verify(msg.as_str(), &sigs(&keys[..2], msg.as_str())),
+            Ok(())
+        );
+        assert_eq!(
+            verify_release(&set, 5, HASH, &sigs(&keys[1..], msg.as_str()), 0),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn threshold_not_met() {
+        let keys = [key(1), key(2), key(3)];
+        let set = key_set(0, 2, &keys);
+        let msg = release_message(5, HASH).unwrap();
+        // one of ours, and one from a key that isn't in the set
+        let sigs = sigs(&[key(1), key(9)], msg.as_str());
+        assert_eq!(
+            set.verify(msg.as_str(), &sigs),
+            Err(VerifyError::NotEnoughSignatures { got: 1, need: 2 })
+        );
+        // signed for another image
+        let other = release_message(6, HASH).unwrap();
+        assert!(set.verify(other.as_str(), &sigs).is_err());
+    }
+
+    #[test]
+    fn same_key_counts_once() {
+        let keys = [key(1), key(2)];
+        let set = key_set(0, 2, &keys);
+        let msg = release_message(5, HASH).unwrap();
+        let sig = sign(&keys[0], msg.as_str());
+        let twice = [sig.as_str(), sig.as_str()].join(" ");
+        assert_eq!(
+            set.verify(msg.as_str(), &twice),
+            Err(VerifyError::NotEnoughSignatures { got: 1, need: 2 })
+        );
+    }
+
+    #[test]
+    fn rotation_needs_a_newer_sequence() {
+        let keys = [key(1), key(2)];
+        let set = key_set(3, 1, &keys);
+        let next_keys = [key(4), key(5)];
+        let next_addresses: Vec<String> = next_keys.iter().map(address).collect();
+        let next_addresses: Vec<&str> = next_addresses.iter().map(|a| a.as_str()).collect();
+        for (sequence, expected) in [
+            (2, Err(VerifyError::StaleSequence)),
+            (3, Err(VerifyError::StaleSequence)),
+            (4, Ok(())),
+        ] {
+            let next = KeySet::new(sequence, 2, &next_addresses).unwrap();
+            let msg = rotation_message(sequence, 2, &next_addresses).unwrap();
+            let sigs = sigs(&keys[..1], msg.as_str());
+            assert_eq!(set.verify_rotation(&next, msg.as_str(), &sigs), expected);
+        }
+    }
+
+    #[test]
+    fn downgrade_needs_its_own_message() {
+        let keys = [key(1)];
+        let set = key_set(0, 1, &keys);
+        let release = sigs(&keys, release_message(4, HASH).unwrap().as_str());
+        assert_eq!(
+            verify_release(&set, 4, HASH, &release, 5),
+            Err(VerifyError::Downgrade { min_version: 5 })
+        );
+        let downgrade = sigs(&keys, downgrade_message(4, HASH).unwrap().as_str());
+        let both = [release.as_str(), downgrade.as_str()].join(" ");
+        assert_eq!(verify_release(&set, 4, HASH, &both, 5), Ok(()));
+        // the downgrade alone isn't a release
+        assert!(verify_release(&set, 4, HASH, &downgrade, 5).is_err());
+    }
+
+    #[test]
+    fn key_set_round_trips() {
+        let set = key_set(7, 2, &[key(1), key(2), key(3)]);
+        let (bytes, len) = set.to_bytes();
+        assert_eq!(KeySet::from_slice(&bytes[..len]), Some(set.clone()));
+        let mut buf = [0u8; 40];
+        assert_eq!(set.address(0, &mut buf), Some(address(&key(1)).as_str()));
+    }
+}
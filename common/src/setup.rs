@@ -0,0 +1,68 @@
+//! The on-device config server, served from the signer's access point.
+
+#[cfg(feature = "provision")]
+use crate::provision::SealedSeed;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+// GET /scan, the networks seen when the access point came up
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanEntry {
+    pub ssid: String,
+    pub rssi: i8,
+    pub auth: String,
+}
+
+// GET /status
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub firmware: String,
+    #[serde(default)]
+    pub ota_version: Option<u64>,
+    pub has_seed: bool,
+    // the device key, that provisioning files are sealed to
+    pub pubkey: String,
+    // the seed is PIN locked, and POST /unlock is waiting for the PIN
+    #[serde(default)]
+    pub locked: bool,
+}
+
+// POST /unlock
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unlock {
+    pub pin: String,
+}
+
+// GET /mnemonic, a fresh seed made on the signer. Shown only once, the
+// words at the challenge positions must be sent back to keep it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MnemonicChallenge {
+    pub words: Vec<String>,
+    // zero based word positions
+    pub challenge: Vec<usize>,
+}
+
+// POST /mnemonic/verify, the words at the challenge positions, in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MnemonicAnswer {
+    pub words: Vec<String>,
+}
+
+// POST /generate, for a seed made on the signer that is never sent to it.
+// The seed is only kept once the response, with its backup, is out
+#[cfg(feature = "provision")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerateRequest {
+    pub network: String,
+    // the seed is sealed to this, and only the holder of its secret can read the backup
+    pub backup_pubkey: String,
+}
+
+#[cfg(feature = "provision")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerateResponse {
+    pub node_pubkey: String,
+    // SealedSeed::Device, with the signer's one time ECDH pubkey
+    pub backup: SealedSeed,
+}
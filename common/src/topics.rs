@@ -0,0 +1,29 @@
+//! Topics on top of the ones in `sphinx_glyph::topics`.
+
+// broker -> signer, one piece of a firmware image
+pub const OTA_CHUNK: &str = "ota-chunk";
+// signer -> broker, acks a chunk with the next expected offset
+pub const OTA_CHUNK_RES: &str = "ota-chunk-res";
+// signer -> broker, unsolicited progress of an update
+pub const OTA_PROGRESS: &str = "ota-progress";
+
+// control msgs from ext::ExtControl, and their ext::ExtResponse
+pub const EXT_CONTROL: &str = "ext-control";
+pub const EXT_CONTROL_RES: &str = "ext-control-res";
+
+// signer -> broker, unsolicited, an ext::Approval as it comes in and changes
+pub const APPROVAL: &str = "approval";
+// signer -> broker, unsolicited, an ext::Restart from before this connect
+pub const RESTART: &str = "restart";
+// signer -> broker, unsolicited, an ext::HeapWarning
+pub const HEAP: &str = "heap";
+
+pub const SIGNER_SUBS: [&str; 2] = [OTA_CHUNK, EXT_CONTROL];
+pub const BROKER_SUBS: [&str; 6] = [
+    OTA_CHUNK_RES,
+    OTA_PROGRESS,
+    EXT_CONTROL_RES,
+    APPROVAL,
+    RESTART,
+    HEAP,
+];
@@ -1,4 +1,4 @@
-use crate::{colors::*, FactoryError};
+use crate::FactoryError;
 use core::time::Duration;
 use esp_idf_svc::hal::{
     delay::FreeRtos,
@@ -6,41 +6,69 @@ use esp_idf_svc::hal::{
     rmt::{config::TransmitConfig, FixedLengthSignal, PinState, Pulse, TxRmtDriver, CHANNEL0},
     sys::EspError,
 };
+use sphinx_key_common::led::*;
 
 pub(crate) struct Peripherals {
     pub led: Gpio0,
     pub channel: CHANNEL0,
 }
 
-pub(crate) fn setup(peripherals: Peripherals) -> Result<TxRmtDriver<'static>, FactoryError> {
+pub(crate) struct Led {
+    tx: TxRmtDriver<'static>,
+    // set by the main app, see sphinx-key/src/core/control.rs
+    brightness: u8,
+}
+
+pub(crate) fn setup(peripherals: Peripherals, brightness: u8) -> Result<Led, FactoryError> {
     let led = peripherals.led;
     let channel = peripherals.channel;
     let config = TransmitConfig::new().clock_divider(1);
     let tx = TxRmtDriver::new(channel, led, &config).map_err(FactoryError::Esp)?;
-    Ok(tx)
+    Ok(Led { tx, brightness })
 }
 
-pub(crate) fn setup_complete(led_tx: &mut TxRmtDriver) -> Result<(), FactoryError> {
-    neopixel(BLUE, led_tx).map_err(FactoryError::Esp)?;
-    FreeRtos::delay_ms(10);
-    Ok(())
+pub(crate) fn setup_complete(led: &mut Led) -> Result<(), FactoryError> {
+    show(led, BLUE)
 }
 
-pub(crate) fn update_launch(led_tx: &mut TxRmtDriver) -> Result<(), FactoryError> {
-    neopixel(ORANGE, led_tx).map_err(FactoryError::Esp)?;
-    FreeRtos::delay_ms(10);
-    Ok(())
+pub(crate) fn update_launch(led: &mut Led) -> Result<(), FactoryError> {
+    show(led, ORANGE)
+}
+
+pub(crate) fn update_complete(led: &mut Led) -> Result<(), FactoryError> {
+    show(led, GREEN)
 }
 
-pub(crate) fn update_complete(led_tx: &mut TxRmtDriver) -> Result<(), FactoryError> {
-    neopixel(GREEN, led_tx).map_err(FactoryError::Esp)?;
+// the same blink code as a failed update in the main app, then red
+pub(crate) fn update_rejected(led: &mut Led) -> Result<(), FactoryError> {
+    play(led, Pattern::error(ErrorCode::Ota))?;
+    show(led, RED)
+}
+
+pub(crate) fn rollback_launch(led: &mut Led) -> Result<(), FactoryError> {
+    show(led, PURPLE)
+}
+
+pub(crate) fn main_app_launch(led: &mut Led) -> Result<(), FactoryError> {
+    show(led, WHITE)
+}
+
+fn show(led: &mut Led, rgb: Rgb) -> Result<(), FactoryError> {
+    neopixel(rgb.scaled(led.brightness), &mut led.tx).map_err(FactoryError::Esp)?;
     FreeRtos::delay_ms(10);
     Ok(())
 }
 
-pub(crate) fn main_app_launch(led_tx: &mut TxRmtDriver) -> Result<(), FactoryError> {
-    neopixel(WHITE, led_tx).map_err(FactoryError::Esp)?;
-    FreeRtos::delay_ms(10);
+// until the repeats run out, so never for a pattern without any
+fn play(led: &mut Led, pattern: Pattern) -> Result<(), FactoryError> {
+    let steps = pattern.steps().len() * pattern.repeat as usize;
+    let mut player = Player::new(led.brightness);
+    player.play(pattern);
+    for _ in 0..steps {
+        let step = player.step();
+        neopixel(step.color, &mut led.tx).map_err(FactoryError::Esp)?;
+        FreeRtos::delay_ms(step.millis.into());
+    }
     Ok(())
 }
 
@@ -53,7 +81,7 @@ fn neopixel(rgb: Rgb, tx: &mut TxRmtDriver) -> Result<(), EspError> {
     // G        R        B
     // 7      0 7      0 7      0
     // 00000010 00000001 00000100
-    let color: u32 = ((rgb.g as u32) << 16) | ((rgb.r as u32) << 8) | rgb.b as u32;
+    let color = rgb.grb();
     let ticks_hz = tx.counter_clock()?;
     let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(350))?;
     let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(800))?;
@@ -1,8 +1,8 @@
 #![no_std]
 #![no_main]
 
-mod colors;
 mod led;
+mod nvs;
 mod ota;
 mod sdcard;
 
@@ -12,12 +12,17 @@ use esp_idf_svc::{
     sys::EspError,
 };
 use esp_println::println;
+use sphinx_key_common::release::VerifyError;
+
+// restarts of a freshly flashed main app before it reverts to the previous image
+const MAX_BOOT_ATTEMPTS: u8 = 3;
 
 #[derive(Debug)]
 pub(crate) enum FactoryError {
     SdCard(Error<SdCardError>),
     Ota(EspError),
     Esp(EspError),
+    Verify(VerifyError),
 }
 
 #[no_mangle]
@@ -28,21 +33,67 @@ fn main() -> Result<(), FactoryError> {
     println!("Assigned peripherals");
     let mut manager = sdcard::setup(sd_card_peripherals)?;
     println!("Setup sdcard");
-    let mut led_tx = led::setup(led_peripherals)?;
+    let mut nvs = nvs::Nvs::open()?;
+    let mut led = led::setup(led_peripherals, nvs.led_brightness()?)?;
     println!("Setup led");
-    led::setup_complete(&mut led_tx)?; // BLUE
+    led::setup_complete(&mut led)?; // BLUE
     println!("Setup complete");
     FreeRtos::delay_ms(5000u32);
     if ota::update_present(&mut manager)? {
-        led::update_launch(&mut led_tx)?; // ORANGE
+        led::update_launch(&mut led)?; // ORANGE
         println!("Update present, proceeding with update");
-        ota::write_update(&mut manager)?;
-        led::update_complete(&mut led_tx)?; // GREEN
-        println!("Update finished, restarting the chip");
+        match ota::write_update(&mut manager, &nvs) {
+            Ok(version) => {
+                ota::keep_update(&mut manager, &mut nvs)?;
+                nvs.set_fw_version(version)?;
+                nvs.set_boot_failures(0)?;
+                led::update_complete(&mut led)?; // GREEN
+                println!(
+                    "Update to version {} finished, restarting the chip",
+                    version
+                );
+            }
+            Err(FactoryError::Verify(e)) => {
+                led::update_rejected(&mut led)?; // RED
+                println!("Update rejected: {}, setting boot to main app", e);
+                ota::set_boot_main_app()?;
+            }
+            Err(e) => return Err(e),
+        }
+        ota::remove_update(&mut manager)?;
+    } else if ota::main_app_failed()? {
+        let failures = nvs.boot_failures()?.saturating_add(1);
+        println!(
+            "Main app restarted before coming up, attempt {} of {}",
+            failures, MAX_BOOT_ATTEMPTS
+        );
+        if failures < MAX_BOOT_ATTEMPTS || !ota::previous_present(&mut manager)? {
+            nvs.set_boot_failures(failures)?;
+            ota::set_boot_main_app()?;
+            led::main_app_launch(&mut led)?; // WHITE
+            println!("Boot set to main app");
+        } else {
+            led::rollback_launch(&mut led)?; // PURPLE
+            println!("Rolling back to the previous image");
+            match ota::rollback(&mut manager, &mut nvs) {
+                Ok(version) => {
+                    nvs.set_fw_version(version)?;
+                    led::update_complete(&mut led)?; // GREEN
+                    println!("Rolled back to version {}, restarting the chip", version);
+                }
+                Err(FactoryError::Verify(e)) => {
+                    led::update_rejected(&mut led)?; // RED
+                    println!("Previous image rejected: {}, setting boot to main app", e);
+                    ota::set_boot_main_app()?;
+                }
+                Err(e) => return Err(e),
+            }
+            nvs.set_boot_failures(0)?;
+        }
     } else {
         println!("No update present, setting boot to main app");
         ota::set_boot_main_app()?;
-        led::main_app_launch(&mut led_tx)?; // WHITE
+        led::main_app_launch(&mut led)?; // WHITE
         println!("Boot set to main app");
     }
     println!("Restarting esp");
@@ -0,0 +1,146 @@
+use crate::FactoryError;
+use esp_idf_svc::sys::{
+    esp, esp_err_t, nvs_close, nvs_commit, nvs_erase_key, nvs_flash_init, nvs_get_blob,
+    nvs_handle_t, nvs_open, nvs_open_mode_t_NVS_READWRITE, nvs_set_blob, ESP_ERR_NVS_NOT_FOUND,
+};
+use sphinx_key_common::led::DEFAULT_BRIGHTNESS;
+use sphinx_key_common::release::{KeySet, KEYSET_LEN};
+
+// shared with the main app, see sphinx-key/src/core/control.rs
+const NAMESPACE: &[u8] = b"sphinx\0";
+const MIN_VERSION_KEY: &[u8] = b"minver\0";
+const FW_VERSION_KEY: &[u8] = b"fwver\0";
+const OTA_KEYS_KEY: &[u8] = b"otakeys\0";
+const LED_BRIGHTNESS_KEY: &[u8] = b"ledbright\0";
+// only written by the factory app, reset each time an image is flashed
+const BOOT_FAILURES_KEY: &[u8] = b"bootfails\0";
+// the sha256 in the manifest of the image kept as previous.bin, as hex
+const PREVIOUS_SHA_KEY: &[u8] = b"prevsha\0";
+pub(crate) const SHA_HEX_LEN: usize = 64;
+
+const NOT_FOUND: esp_err_t = ESP_ERR_NVS_NOT_FOUND as esp_err_t;
+
+pub(crate) struct Nvs(nvs_handle_t);
+
+impl Nvs {
+    pub(crate) fn open() -> Result<Self, FactoryError> {
+        esp!(unsafe { nvs_flash_init() }).map_err(FactoryError::Esp)?;
+        let mut handle: nvs_handle_t = 0;
+        esp!(unsafe {
+            nvs_open(
+                NAMESPACE.as_ptr() as *const _,
+                nvs_open_mode_t_NVS_READWRITE,
+                &mut handle,
+            )
+        })
+        .map_err(FactoryError::Esp)?;
+        Ok(Self(handle))
+    }
+
+    fn get_blob<'a>(
+        &self,
+        key: &[u8],
+        buf: &'a mut [u8],
+    ) -> Result<Option<&'a [u8]>, FactoryError> {
+        let mut len = buf.len();
+        let err = unsafe {
+            nvs_get_blob(
+                self.0,
+                key.as_ptr() as *const _,
+                buf.as_mut_ptr() as *mut _,
+                &mut len,
+            )
+        };
+        if err == NOT_FOUND {
+            return Ok(None);
+        }
+        esp!(err).map_err(FactoryError::Esp)?;
+        Ok(Some(&buf[..len]))
+    }
+
+    fn set_blob(&mut self, key: &[u8], value: &[u8]) -> Result<(), FactoryError> {
+        esp!(unsafe {
+            nvs_set_blob(
+                self.0,
+                key.as_ptr() as *const _,
+                value.as_ptr() as *const _,
+                value.len(),
+            )
+        })
+        .map_err(FactoryError::Esp)?;
+        esp!(unsafe { nvs_commit(self.0) }).map_err(FactoryError::Esp)
+    }
+
+    pub(crate) fn min_version(&self) -> Result<u64, FactoryError> {
+        let mut buf = [0u8; 8];
+        let min = self
+            .get_blob(MIN_VERSION_KEY, &mut buf)?
+            .and_then(|b| b.try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+        Ok(min)
+    }
+
+    // the version of the image flashed, which the main app reports and
+    // raises the minimum to once the image has come up
+    pub(crate) fn set_fw_version(&mut self, version: u64) -> Result<(), FactoryError> {
+        self.set_blob(FW_VERSION_KEY, &version.to_be_bytes())
+    }
+
+    pub(crate) fn previous_sha256<'a>(
+        &self,
+        buf: &'a mut [u8; SHA_HEX_LEN],
+    ) -> Result<Option<&'a [u8]>, FactoryError> {
+        self.get_blob(PREVIOUS_SHA_KEY, buf)
+    }
+
+    pub(crate) fn set_previous_sha256(&mut self, sha256: Option<&str>) -> Result<(), FactoryError> {
+        match sha256 {
+            Some(sha256) => self.set_blob(PREVIOUS_SHA_KEY, sha256.as_bytes()),
+            None => {
+                let err = unsafe { nvs_erase_key(self.0, PREVIOUS_SHA_KEY.as_ptr() as *const _) };
+                if err != NOT_FOUND {
+                    esp!(err).map_err(FactoryError::Esp)?;
+                }
+                esp!(unsafe { nvs_commit(self.0) }).map_err(FactoryError::Esp)
+            }
+        }
+    }
+
+    pub(crate) fn boot_failures(&self) -> Result<u8, FactoryError> {
+        let mut buf = [0u8; 1];
+        let failures = self
+            .get_blob(BOOT_FAILURES_KEY, &mut buf)?
+            .and_then(|b| b.first().copied())
+            .unwrap_or(0);
+        Ok(failures)
+    }
+
+    pub(crate) fn set_boot_failures(&mut self, failures: u8) -> Result<(), FactoryError> {
+        self.set_blob(BOOT_FAILURES_KEY, &[failures])
+    }
+
+    pub(crate) fn led_brightness(&self) -> Result<u8, FactoryError> {
+        let mut buf = [0u8; 1];
+        let brightness = self
+            .get_blob(LED_BRIGHTNESS_KEY, &mut buf)?
+            .and_then(|b| b.first().copied())
+            .unwrap_or(DEFAULT_BRIGHTNESS);
+        Ok(brightness)
+    }
+
+    pub(crate) fn ota_keys(&self) -> Result<KeySet, FactoryError> {
+        let mut buf = [0u8; KEYSET_LEN];
+        let keys = self
+            .get_blob(OTA_KEYS_KEY, &mut buf)?
+            .and_then(KeySet::from_slice)
+            .unwrap_or_else(KeySet::compiled);
+        Ok(keys)
+    }
+}
+
+impl Drop for Nvs {
+    fn drop(&mut self) {
+        unsafe { nvs_close(self.0) };
+    }
+}
@@ -1,23 +1,59 @@
+use crate::nvs::{Nvs, SHA_HEX_LEN};
 use crate::sdcard::Manager;
 use crate::FactoryError;
 use core::ptr;
 use embedded_sdmmc::{Error::FileNotFound, Mode, VolumeIdx};
 use esp_idf_svc::{
     ota::EspOta,
-    sys::{esp, esp_ota_get_next_update_partition, esp_ota_set_boot_partition},
+    sys::{
+        esp, esp_err_t, esp_ota_get_next_update_partition, esp_ota_get_state_partition,
+        esp_ota_img_states_t, esp_ota_img_states_t_ESP_OTA_IMG_ABORTED,
+        esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY, esp_ota_set_boot_partition,
+        ESP_ERR_NOT_FOUND,
+    },
 };
+use esp_println::println;
+use sphinx_key_common::ota::Manifest;
+use sphinx_key_common::release::{verify_release, ImageHash, VerifyError};
 
-const FILE: &str = "update.bin";
+// a firmware image and the manifest the main app writes next to it,
+// see sphinx_key_common::ota::Manifest
+struct Image {
+    bin: &'static str,
+    man: &'static str,
+}
+
+const UPDATE: Image = Image {
+    bin: "update.bin",
+    man: "update.man",
+};
+// the image last flashed, and the one before it to fall back to
+const CURRENT: Image = Image {
+    bin: "current.bin",
+    man: "current.man",
+};
+const PREVIOUS: Image = Image {
+    bin: "previous.bin",
+    man: "previous.man",
+};
 const BUFFER_LEN: usize = 1024;
 
 pub(crate) fn update_present(volume_mgr: &mut Manager) -> Result<bool, FactoryError> {
+    file_present(volume_mgr, UPDATE.bin)
+}
+
+pub(crate) fn previous_present(volume_mgr: &mut Manager) -> Result<bool, FactoryError> {
+    file_present(volume_mgr, PREVIOUS.bin)
+}
+
+fn file_present(volume_mgr: &mut Manager, name: &str) -> Result<bool, FactoryError> {
     let volume0 = volume_mgr
         .get_volume(VolumeIdx(0))
         .map_err(FactoryError::SdCard)?;
     let root_dir = volume_mgr
         .open_root_dir(&volume0)
         .map_err(FactoryError::SdCard)?;
-    let ret = match volume_mgr.find_directory_entry(&volume0, &root_dir, FILE) {
+    let ret = match volume_mgr.find_directory_entry(&volume0, &root_dir, name) {
         Ok(_) => Ok(true),
         Err(FileNotFound) => Ok(false),
         Err(e) => Err(FactoryError::SdCard(e)),
@@ -26,37 +62,244 @@ pub(crate) fn update_present(volume_mgr: &mut Manager) -> Result<bool, FactoryEr
     ret
 }
 
-pub(crate) fn write_update(volume_mgr: &mut Manager) -> Result<(), FactoryError> {
+pub(crate) fn write_update(volume_mgr: &mut Manager, nvs: &Nvs) -> Result<u64, FactoryError> {
+    let min_version = nvs.min_version()?;
+    write_image(volume_mgr, nvs, &UPDATE, min_version, None)
+}
+
+// only the image keep_update set aside, anything else put at previous.bin
+// could be any old release. The minimum version still applies, the main
+// app only raises it once an update has come up
+pub(crate) fn rollback(volume_mgr: &mut Manager, nvs: &mut Nvs) -> Result<u64, FactoryError> {
+    let mut buf = [0u8; SHA_HEX_LEN];
+    let kept = nvs
+        .previous_sha256(&mut buf)?
+        .ok_or(FactoryError::Verify(VerifyError::HashMismatch))?;
+    let version = write_image(volume_mgr, nvs, &PREVIOUS, nvs.min_version()?, Some(kept))?;
+    copy_image(volume_mgr, &PREVIOUS, &CURRENT)?;
+    remove_image(volume_mgr, &PREVIOUS)?;
+    nvs.set_previous_sha256(None)?;
+    Ok(version)
+}
+
+// the manifest is checked against the release keys, and the image is hashed
+// once before anything is erased and again while it is flashed
+fn write_image(
+    volume_mgr: &mut Manager,
+    nvs: &Nvs,
+    image: &Image,
+    min_version: u64,
+    sha256: Option<&[u8]>,
+) -> Result<u64, FactoryError> {
+    let mut buffer = [0u8; Manifest::MAX_LEN];
+    let manifest = read_manifest(volume_mgr, image.man, &mut buffer)?;
+    if sha256.is_some_and(|s| s != manifest.sha256.as_bytes()) {
+        return Err(FactoryError::Verify(VerifyError::HashMismatch));
+    }
+    verify_release(
+        &nvs.ota_keys()?,
+        manifest.version,
+        manifest.sha256,
+        manifest.sigs,
+        min_version,
+    )
+    .map_err(FactoryError::Verify)?;
+    println!("Manifest for version {} verified", manifest.version);
+
+    let mut hash = ImageHash::default();
+    read_file(volume_mgr, image.bin, |chunk| {
+        hash.update(chunk);
+        Ok(())
+    })?;
+    hash.check(manifest.sha256).map_err(FactoryError::Verify)?;
+    println!("Image verified, flashing");
+
+    let mut ota = EspOta::new().map_err(FactoryError::Ota)?;
+    let mut ota = ota.initiate_update().map_err(FactoryError::Ota)?;
+    let mut hash = ImageHash::default();
+    read_file(volume_mgr, image.bin, |chunk| {
+        hash.update(chunk);
+        ota.write(chunk).map_err(FactoryError::Ota)?;
+        Ok(())
+    })?;
+    if let Err(e) = hash.check(manifest.sha256) {
+        // changed on the sdcard since the first read
+        ota.abort().map_err(FactoryError::Ota)?;
+        return Err(FactoryError::Verify(e));
+    }
+
+    ota.complete().map_err(FactoryError::Ota)?;
+    Ok(manifest.version)
+}
+
+// once flashed, the update becomes the current image and the current one
+// is kept around as the previous image, recorded by its hash so rollback
+// only ever flashes that one
+pub(crate) fn keep_update(volume_mgr: &mut Manager, nvs: &mut Nvs) -> Result<(), FactoryError> {
+    nvs.set_previous_sha256(None)?;
+    remove_image(volume_mgr, &PREVIOUS)?;
+    if file_present(volume_mgr, CURRENT.bin)? {
+        let mut buffer = [0u8; Manifest::MAX_LEN];
+        match read_manifest(volume_mgr, CURRENT.man, &mut buffer) {
+            Ok(manifest) => {
+                copy_image(volume_mgr, &CURRENT, &PREVIOUS)?;
+                nvs.set_previous_sha256(Some(manifest.sha256))?;
+            }
+            Err(FactoryError::Verify(_)) => println!("No manifest for the current image"),
+            Err(e) => return Err(e),
+        }
+    }
+    copy_image(volume_mgr, &UPDATE, &CURRENT)
+}
+
+// applied or rejected, so it is not tried again on the next boot
+pub(crate) fn remove_update(volume_mgr: &mut Manager) -> Result<(), FactoryError> {
+    remove_image(volume_mgr, &UPDATE)
+}
+
+fn remove_image(volume_mgr: &mut Manager, image: &Image) -> Result<(), FactoryError> {
+    let volume0 = volume_mgr
+        .get_volume(VolumeIdx(0))
+        .map_err(FactoryError::SdCard)?;
+    let root_dir = volume_mgr
+        .open_root_dir(&volume0)
+        .map_err(FactoryError::SdCard)?;
+    let mut ret = Ok(());
+    for name in [image.bin, image.man] {
+        match volume_mgr.delete_file_in_dir(&volume0, &root_dir, name) {
+            Ok(()) | Err(FileNotFound) => (),
+            Err(e) => ret = Err(FactoryError::SdCard(e)),
+        }
+    }
+    volume_mgr.close_dir(&volume0, root_dir);
+    ret
+}
+
+fn copy_image(volume_mgr: &mut Manager, from: &Image, to: &Image) -> Result<(), FactoryError> {
+    copy_file(volume_mgr, from.bin, to.bin)?;
+    copy_file(volume_mgr, from.man, to.man)
+}
+
+fn read_manifest<'a>(
+    volume_mgr: &mut Manager,
+    name: &str,
+    buffer: &'a mut [u8; Manifest::MAX_LEN],
+) -> Result<Manifest<'a>, FactoryError> {
+    let bad_manifest = FactoryError::Verify(VerifyError::BadManifest);
+    let mut len = 0;
+    let res = read_file(volume_mgr, name, |chunk| {
+        let end = len + chunk.len();
+        if end > buffer.len() {
+            return Err(FactoryError::Verify(VerifyError::BadManifest));
+        }
+        buffer[len..end].copy_from_slice(chunk);
+        len = end;
+        Ok(())
+    });
+    match res {
+        Ok(()) => (),
+        Err(FactoryError::SdCard(FileNotFound)) => return Err(bad_manifest),
+        Err(e) => return Err(e),
+    }
+    let text = core::str::from_utf8(&buffer[..len]).map_err(|_| bad_manifest)?;
+    Manifest::parse(text).ok_or(FactoryError::Verify(VerifyError::BadManifest))
+}
+
+fn read_file(
+    volume_mgr: &mut Manager,
+    name: &str,
+    mut f: impl FnMut(&[u8]) -> Result<(), FactoryError>,
+) -> Result<(), FactoryError> {
     let mut volume0 = volume_mgr
         .get_volume(VolumeIdx(0))
         .map_err(FactoryError::SdCard)?;
     let root_dir = volume_mgr
         .open_root_dir(&volume0)
         .map_err(FactoryError::SdCard)?;
-    let mut my_file = volume_mgr
-        .open_file_in_dir(&mut volume0, &root_dir, FILE, Mode::ReadOnly)
+    let mut my_file =
+        match volume_mgr.open_file_in_dir(&mut volume0, &root_dir, name, Mode::ReadOnly) {
+            Ok(file) => file,
+            Err(e) => {
+                volume_mgr.close_dir(&volume0, root_dir);
+                return Err(FactoryError::SdCard(e));
+            }
+        };
+
+    let mut buffer = [0u8; BUFFER_LEN];
+    let mut ret = Ok(());
+    while !my_file.eof() {
+        let r = match volume_mgr.read(&volume0, &mut my_file, &mut buffer) {
+            Ok(r) => r,
+            Err(e) => {
+                ret = Err(FactoryError::SdCard(e));
+                break;
+            }
+        };
+        if let Err(e) = f(&buffer[..r]) {
+            ret = Err(e);
+            break;
+        }
+    }
+
+    volume_mgr
+        .close_file(&volume0, my_file)
         .map_err(FactoryError::SdCard)?;
+    volume_mgr.close_dir(&volume0, root_dir);
+    ret
+}
 
-    let mut ota = EspOta::new().map_err(FactoryError::Ota)?;
-    let mut ota = ota.initiate_update().map_err(FactoryError::Ota)?;
+// embedded_sdmmc has no rename, so images are moved around by copying
+fn copy_file(volume_mgr: &mut Manager, from: &str, to: &str) -> Result<(), FactoryError> {
+    let mut volume0 = volume_mgr
+        .get_volume(VolumeIdx(0))
+        .map_err(FactoryError::SdCard)?;
+    let root_dir = volume_mgr
+        .open_root_dir(&volume0)
+        .map_err(FactoryError::SdCard)?;
+    let mut src = volume_mgr
+        .open_file_in_dir(&mut volume0, &root_dir, from, Mode::ReadOnly)
+        .map_err(FactoryError::SdCard)?;
+    let mut dst = volume_mgr
+        .open_file_in_dir(&mut volume0, &root_dir, to, Mode::ReadWriteCreateOrTruncate)
+        .map_err(FactoryError::SdCard)?;
 
     let mut buffer = [0u8; BUFFER_LEN];
-    while !my_file.eof() {
+    while !src.eof() {
         let r = volume_mgr
-            .read(&volume0, &mut my_file, &mut buffer)
+            .read(&volume0, &mut src, &mut buffer)
+            .map_err(FactoryError::SdCard)?;
+        volume_mgr
+            .write(&mut volume0, &mut dst, &buffer[..r])
             .map_err(FactoryError::SdCard)?;
-        ota.write(&buffer[..r]).map_err(FactoryError::Ota)?;
     }
 
-    ota.complete().map_err(FactoryError::Ota)?;
-
     volume_mgr
-        .close_file(&volume0, my_file)
+        .close_file(&volume0, src)
+        .map_err(FactoryError::SdCard)?;
+    volume_mgr
+        .close_file(&volume0, dst)
         .map_err(FactoryError::SdCard)?;
     volume_mgr.close_dir(&volume0, root_dir);
     Ok(())
 }
 
+// true if the main app was flashed but restarted before marking itself valid,
+// see CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE
+pub(crate) fn main_app_failed() -> Result<bool, FactoryError> {
+    let mut state: esp_ota_img_states_t = 0;
+    let err = unsafe {
+        let partition = esp_ota_get_next_update_partition(ptr::null());
+        esp_ota_get_state_partition(partition, &mut state)
+    };
+    // no otadata entry, the main app was flashed over the wire
+    if err == ESP_ERR_NOT_FOUND as esp_err_t {
+        return Ok(false);
+    }
+    esp!(err).map_err(FactoryError::Ota)?;
+    Ok(state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+        || state == esp_ota_img_states_t_ESP_OTA_IMG_ABORTED)
+}
+
 pub(crate) fn set_boot_main_app() -> Result<(), FactoryError> {
     esp!(unsafe {
         let partition = esp_ota_get_next_update_partition(ptr::null());
@@ -1,25 +1,46 @@
-use crate::core::FlashPersister;
+use crate::core::{reset, FlashPersister};
 use crate::status::Status;
 use anyhow::Result;
 use esp_idf_svc::hal::gpio;
 use esp_idf_svc::hal::gpio::*;
-use sphinx_signer::sphinx_glyph::control::ControlPersist;
+use sphinx_key_common::ext::{ButtonAction, ResetMode};
+use sphinx_key_common::gesture::Detector;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const MILLIS: u16 = 10_000;
 
 const PAUSE: u16 = 50;
 
-// progression is waiting -> *starting -> reset1a -> reset1 -> reset2a -> reset2 -> reset3
-// state machine initialized at starting
+const MODES: [ResetMode; 4] = [
+    ResetMode::Wifi,
+    ResetMode::Policy,
+    ResetMode::Store,
+    ResetMode::Full,
+];
+
+// for the request waiting on the signer, if any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Approve,
+    Reject,
+}
+
+// short, double and long presses do what FlashPersister::read_button_actions
+// says, while the progression is idle at starting. A reset action picks the
+// mode the same way reset3 does. Alongside them, the progression is
+// waiting -> *starting -> reset1a -> reset1 -> reset2a -> reset2 -> reset3
+// state machine initialized at starting. At reset3 each press steps through
+// the reset modes, and the one showing after MILLIS without a press is wiped
 pub fn button_loop(
     gpio9: gpio::Gpio9,
     tx: mpsc::Sender<Status>,
     flash_arc: Arc<Mutex<FlashPersister>>,
+    decisions: mpsc::Sender<Decision>,
 ) -> Result<()> {
-    let builder = thread::Builder::new().stack_size(2500);
+    // room for reading the actions as json, and for a wipe
+    let builder = thread::Builder::new().stack_size(4096);
     builder.spawn(move || {
         let mut button = PinDriver::input(gpio9).unwrap();
         button.set_pull(Pull::Up).unwrap();
@@ -27,20 +48,44 @@ pub fn button_loop(
         let mut up_times = 0;
         let mut low_times = 0;
         let mut machine = Machine::new(tx, Status::Starting);
+        let mut detector = Detector::default();
+        let start = Instant::now();
         loop {
             // we are using thread::sleep here to make sure the watchdog isn't triggered
             if machine.state == Status::Reset3 {
-                let mut flash = flash_arc.lock().unwrap();
-                if let Err(_e) = flash.remove_config() {
-                    log::error!("could not clear wifi config");
-                    drop(flash);
-                } else {
-                    log::info!("restarting esp!");
-                    drop(flash);
-                    unsafe { esp_idf_svc::sys::esp_restart() };
-                }
+                let mode = machine.select_mode(&button, ResetMode::Wifi);
+                wipe_and_restart(&flash_arc, mode, &machine.tx);
+                machine.update_status(Status::Starting);
             }
             thread::sleep(Duration::from_millis(PAUSE.into()));
+            let now = start.elapsed().as_millis() as u32;
+            // gestures only count while the reset progression is idle
+            let gesture = if machine.state == Status::Starting {
+                detector.update(button.is_low(), now)
+            } else {
+                detector = Detector::default();
+                None
+            };
+            if let Some(gesture) = gesture {
+                let action = flash_arc.lock().unwrap().read_button_actions().get(gesture);
+                log::info!("=> {:?}: {:?}", gesture, action);
+                match action {
+                    ButtonAction::Nothing => (),
+                    ButtonAction::Approve => decide(&decisions, Decision::Approve),
+                    ButtonAction::Reject => decide(&decisions, Decision::Reject),
+                    ButtonAction::ShowStatus => {
+                        let _ = machine.tx.send(Status::ShowStatus);
+                    }
+                    // confirmed like reset3, starting from this mode
+                    ButtonAction::Reset(mode) => {
+                        let mode = machine.select_mode(&button, mode);
+                        wipe_and_restart(&flash_arc, mode, &machine.tx);
+                        machine.update_status(Status::Starting);
+                        detector = Detector::default();
+                        low_times = 0;
+                    }
+                }
+            }
             if button.is_high() {
                 if pressed {
                     pressed = false;
@@ -120,6 +165,23 @@ pub fn button_loop(
     Ok(())
 }
 
+fn decide(decisions: &mpsc::Sender<Decision>, decision: Decision) {
+    if decisions.send(decision).is_err() {
+        log::warn!("nothing to take the {:?}", decision);
+    }
+}
+
+// only returns if the wipe failed
+fn wipe_and_restart(flash_arc: &Mutex<FlashPersister>, mode: ResetMode, tx: &mpsc::Sender<Status>) {
+    let mut flash = flash_arc.lock().unwrap();
+    let res = reset::wipe(&mut flash, mode).and_then(|_| flash.set_last_reset(mode));
+    drop(flash);
+    match res {
+        Ok(()) => reset::confirm_and_restart(mode, tx),
+        Err(e) => log::error!("could not wipe {:?}: {:?}", mode, e),
+    }
+}
+
 struct Machine {
     tx: mpsc::Sender<Status>,
     state: Status,
@@ -131,6 +193,31 @@ impl Machine {
         tx.send(state).unwrap();
         Self { tx, state }
     }
+    // from reset3 wifi first, so waiting it out does what reset3 always did.
+    // A press that is still held when it starts doesn't step
+    fn select_mode(
+        &mut self,
+        button: &PinDriver<gpio::Gpio9, Input>,
+        first: ResetMode,
+    ) -> ResetMode {
+        let mut i = MODES.iter().position(|m| *m == first).unwrap_or(0);
+        let mut idle = 0;
+        let mut was_pressed = button.is_low();
+        self.update_status(Status::Reset(MODES[i]));
+        while PAUSE * idle <= MILLIS {
+            thread::sleep(Duration::from_millis(PAUSE.into()));
+            let pressed = button.is_low();
+            if pressed && !was_pressed {
+                i = (i + 1) % MODES.len();
+                idle = 0;
+                self.update_status(Status::Reset(MODES[i]));
+            } else if !pressed {
+                idle += 1;
+            }
+            was_pressed = pressed;
+        }
+        MODES[i]
+    }
     fn update_status(&mut self, new_state: Status) {
         if self.state != new_state {
             log::info!("send {:?}", new_state);
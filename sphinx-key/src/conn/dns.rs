@@ -0,0 +1,100 @@
+use anyhow::Result;
+use log::*;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const TTL: u32 = 60;
+
+// answers every A query with the access point address, so whatever a phone
+// or laptop opens after joining the access point lands on the config page.
+// Stops when dropped
+pub struct CaptiveDns {
+    stop: Arc<AtomicBool>,
+}
+
+impl CaptiveDns {
+    pub fn start(ip: Ipv4Addr) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:53")?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let builder = thread::Builder::new().stack_size(3072);
+        builder.spawn(move || {
+            info!("captive DNS answering with {}", ip);
+            let mut buf = [0u8; 512];
+            while !stopped.load(Ordering::Relaxed) {
+                // times out every second to check for the stop
+                let (len, from) = match socket.recv_from(&mut buf) {
+                    Ok(r) => r,
+                    Err(_) => continue,
+                };
+                if let Some(res) = answer(&buf[..len], ip) {
+                    if let Err(e) = socket.send_to(&res, from) {
+                        warn!("captive DNS failed to answer {}: {}", from, e);
+                    }
+                }
+            }
+        })?;
+        Ok(Self { stop })
+    }
+}
+
+impl Drop for CaptiveDns {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// the first question echoed back, with an A record for `ip` if it asked for one
+fn answer(req: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if req.len() < HEADER_LEN {
+        return None;
+    }
+    let is_query = req[2] & 0x80 == 0;
+    let qdcount = u16::from_be_bytes([req[4], req[5]]);
+    if !is_query || qdcount == 0 {
+        return None;
+    }
+    // the name is a run of length prefixed labels, ending in a zero
+    let mut pos = HEADER_LEN;
+    loop {
+        let label = *req.get(pos)? as usize;
+        if label & 0xc0 != 0 {
+            // no compression in a question
+            return None;
+        }
+        pos += 1 + label;
+        if label == 0 {
+            break;
+        }
+    }
+    let question = req.get(HEADER_LEN..pos + 4)?;
+    let qtype = u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+    let ancount: u16 = if qtype == TYPE_A { 1 } else { 0 };
+
+    let mut res = Vec::with_capacity(HEADER_LEN + question.len() + 16);
+    res.extend_from_slice(&req[0..2]);
+    // a response, with recursion desired copied over and available set
+    res.push(0x80 | (req[2] & 0x01));
+    res.push(0x80);
+    res.extend_from_slice(&1u16.to_be_bytes());
+    res.extend_from_slice(&ancount.to_be_bytes());
+    res.extend_from_slice(&[0, 0, 0, 0]);
+    res.extend_from_slice(question);
+    if ancount == 1 {
+        // a pointer to the name in the question
+        res.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        res.extend_from_slice(&TYPE_A.to_be_bytes());
+        res.extend_from_slice(&CLASS_IN.to_be_bytes());
+        res.extend_from_slice(&TTL.to_be_bytes());
+        res.extend_from_slice(&4u16.to_be_bytes());
+        res.extend_from_slice(&ip.octets());
+    }
+    Some(res)
+}
@@ -1,12 +1,20 @@
-use crate::core::config::{decrypt_seed, ecdh_keypair, ConfigDTO};
+use crate::core::config::{decrypt_seed, ecdh_keypair, ConfigDTO, ConfigSeedPin, GeneratedSeed};
+use crate::core::pin;
+use crate::core::FlashPersister;
 use anyhow::Result;
 use esp_idf_svc::http::server::HandlerError;
 use esp_idf_svc::http::server::{Configuration, EspHttpServer};
 use esp_idf_svc::http::Method;
 use serde::Deserialize;
-use sphinx_signer::sphinx_glyph::control::Config;
+use sphinx_key_common::ext::WifiNetwork;
+use sphinx_key_common::setup::{DeviceStatus, GenerateRequest, MnemonicAnswer, ScanEntry, Unlock};
+use std::fmt::Debug;
 use std::sync::{Arc, Condvar, Mutex};
 
+const MAX_BODY_LEN: usize = 2048;
+// provisions from a browser, for phones and laptops without the app
+const PORTAL_HTML: &str = include_str!("portal.html");
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Ecdh {
     pub pubkey: String,
@@ -15,16 +23,47 @@ pub struct Ecdh {
 pub struct Params {
     pub config: String,
 }
+#[derive(Clone, Debug, Deserialize)]
+pub struct WifiParams {
+    pub network: String,
+}
+#[derive(Clone, Debug, Deserialize)]
+pub struct SsidParams {
+    pub ssid: String,
+}
 
 #[allow(clippy::type_complexity)]
 pub fn config_server(
-    mutex: Arc<(Mutex<Option<(Config, Option<[u8; 32]>)>>, Condvar)>,
-    has_stored_seed: bool,
+    mutex: Arc<(Mutex<Option<ConfigSeedPin>>, Condvar)>,
+    flash: Arc<Mutex<FlashPersister>>,
+    scan: Vec<ScanEntry>,
+    status: DeviceStatus,
 ) -> Result<EspHttpServer<'static>> {
     let (sk1, pk1) = ecdh_keypair();
+    let flash1 = flash.clone();
+    let has_stored_seed = status.has_seed;
+    let scan_json = serde_json::to_vec(&scan)?;
+    let status_json = serde_json::to_vec(&status)?;
+    // made by GET /mnemonic or POST /generate, and used by POST /config if
+    // it came without a seed
+    let generated: Arc<Mutex<Option<GeneratedSeed>>> = Arc::new(Mutex::new(None));
+    let generated1 = generated.clone();
+    let generated2 = generated.clone();
+    let generated3 = generated.clone();
 
-    let mut server = EspHttpServer::new(&Configuration::default()).unwrap();
+    let conf = Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    };
+    let mut server = EspHttpServer::new(&conf).unwrap();
     server
+        .fn_handler("/", Method::Get, move |request| {
+            let mut response =
+                request.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?;
+            response.write(PORTAL_HTML.as_bytes())?;
+            response.flush()?;
+            Ok(())
+        })?
         .fn_handler("/ecdh", Method::Get, move |request| {
             let mut response = request.into_ok_response()?;
             response.write(
@@ -33,14 +72,89 @@ pub fn config_server(
             response.flush()?;
             Ok(())
         })?
-        .fn_handler("/config", Method::Post, move |request| {
-            let params =
-                serde_urlencoded::from_str::<Params>(request.uri().split_once('?').unwrap().1)?;
-            let dto = serde_json::from_str::<ConfigDTO>(&params.config)?;
-            let conf_seed_tuple = decrypt_seed(dto, sk1)?;
+        .fn_handler("/scan", Method::Get, move |request| {
+            let mut response = request.into_ok_response()?;
+            response.write(&scan_json)?;
+            response.flush()?;
+            Ok(())
+        })?
+        .fn_handler("/status", Method::Get, move |request| {
+            let mut response = request.into_ok_response()?;
+            response.write(&status_json)?;
+            response.flush()?;
+            Ok(())
+        })?
+        // a new mnemonic each time, so each one is shown only once
+        .fn_handler("/mnemonic", Method::Get, move |request| {
+            if has_stored_seed {
+                return Err(HandlerError::new("seed already stored"));
+            }
+            let seed = GeneratedSeed::generate();
+            let challenge = serde_json::to_vec(&seed.challenge())?;
+            *generated1.lock().unwrap() = Some(seed);
+            let mut response = request.into_ok_response()?;
+            response.write(&challenge)?;
+            response.flush()?;
+            Ok(())
+        })?
+        .fn_handler("/mnemonic/verify", Method::Post, move |mut request| {
+            let body = read_body(|buf| request.read(buf))?;
+            let answer = serde_json::from_slice::<MnemonicAnswer>(&body)?;
+            let mut generated = generated2.lock().unwrap();
+            let seed = generated
+                .as_mut()
+                .ok_or(HandlerError::new("no mnemonic generated"))?;
+            if !seed.confirm(&answer) {
+                return Err(HandlerError::new("wrong words"));
+            }
+            let mut response = request.into_ok_response()?;
+            response.write("{\"success\":true}".as_bytes())?;
+            response.flush()?;
+            Ok(())
+        })?
+        // the seed never leaves the signer, only a backup sealed to the caller's key
+        .fn_handler("/generate", Method::Post, move |mut request| {
+            if has_stored_seed {
+                return Err(HandlerError::new("seed already stored"));
+            }
+            let body = read_body(|buf| request.read(buf))?;
+            let req = serde_json::from_slice::<GenerateRequest>(&body)?;
+            let mut seed = GeneratedSeed::generate();
+            let res = seed.back_up(&req)?;
+            *generated3.lock().unwrap() = Some(seed);
+            let mut response = request.into_ok_response()?;
+            response.write(&serde_json::to_vec(&res)?)?;
+            response.flush()?;
+            Ok(())
+        })?
+        // the config as a json body, or url encoded in the query string
+        .fn_handler("/config", Method::Post, move |mut request| {
+            let body = read_body(|buf| request.read(buf))?;
+            let dto = if body.is_empty() {
+                let query = request.uri().split_once('?').map_or("", |q| q.1);
+                let params = serde_urlencoded::from_str::<Params>(query)?;
+                serde_json::from_str::<ConfigDTO>(&params.config)?
+            } else {
+                serde_json::from_slice::<ConfigDTO>(&body)?
+            };
+            let mut conf_seed_tuple = decrypt_seed(dto, sk1)?;
+            if conf_seed_tuple.1.is_none() {
+                if let Some(g) = generated.lock().unwrap().as_ref() {
+                    if !g.network_matches(&conf_seed_tuple.0.network) {
+                        return Err(HandlerError::new("not the network the seed was made for"));
+                    }
+                    conf_seed_tuple.1 = g.seed();
+                }
+            }
             if !has_stored_seed && conf_seed_tuple.1.is_none() {
                 return Err(HandlerError::new("seed required"));
             }
+            // the PIN runs through a key burned into eFuse, only ever here,
+            // for the config that sets the PIN
+            if let Some(p) = &conf_seed_tuple.2 {
+                pin::check_pin(p)?;
+                pin::burn_device_key()?;
+            }
             let mut wait = mutex.0.lock().unwrap();
             *wait = Some(conf_seed_tuple);
             mutex.1.notify_one();
@@ -48,6 +162,95 @@ pub fn config_server(
             response.write("{\"success\":true}".as_bytes())?;
             response.flush()?;
             Ok(())
+        })?
+        // saved networks to fall back to, besides the one in the config
+        .fn_handler("/wifi", Method::Post, move |request| {
+            let query = request.uri().split_once('?').map_or("", |q| q.1);
+            let network = serde_urlencoded::from_str::<WifiParams>(query)
+                .ok()
+                .and_then(|p| serde_json::from_str::<WifiNetwork>(&p.network).ok());
+            let Some(network) = network else {
+                request.into_response(400, Some("Bad Request"), &[])?;
+                return Ok(());
+            };
+            flash1.lock().unwrap().add_wifi_network(network)?;
+            let mut response = request.into_ok_response()?;
+            response.write("{\"success\":true}".as_bytes())?;
+            response.flush()?;
+            Ok(())
+        })?
+        .fn_handler("/wifi", Method::Delete, move |request| {
+            let query = request.uri().split_once('?').map_or("", |q| q.1);
+            let Ok(params) = serde_urlencoded::from_str::<SsidParams>(query) else {
+                request.into_response(400, Some("Bad Request"), &[])?;
+                return Ok(());
+            };
+            flash.lock().unwrap().remove_wifi_network(&params.ssid)?;
+            let mut response = request.into_ok_response()?;
+            response.write("{\"success\":true}".as_bytes())?;
+            response.flush()?;
+            Ok(())
+        })?
+        // must come last. The captive portal checks of phones and laptops
+        // ask for other pages, and are sent to the portal
+        .fn_handler("/*", Method::Get, move |request| {
+            request.into_response(302, Some("Found"), &[("Location", "/")])?;
+            Ok(())
+        })?;
+    Ok(server)
+}
+
+// while the seed is PIN locked at boot. The portal page asks for the PIN
+// when the status says locked
+pub fn unlock_server(
+    mutex: Arc<(Mutex<Option<[u8; 32]>>, Condvar)>,
+    flash: Arc<Mutex<FlashPersister>>,
+    status: DeviceStatus,
+) -> Result<EspHttpServer<'static>> {
+    let status_json = serde_json::to_vec(&status)?;
+    let mut server = EspHttpServer::new(&Configuration::default()).unwrap();
+    server
+        .fn_handler("/", Method::Get, move |request| {
+            let mut response =
+                request.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?;
+            response.write(PORTAL_HTML.as_bytes())?;
+            response.flush()?;
+            Ok(())
+        })?
+        .fn_handler("/status", Method::Get, move |request| {
+            let mut response = request.into_ok_response()?;
+            response.write(&status_json)?;
+            response.flush()?;
+            Ok(())
+        })?
+        .fn_handler("/unlock", Method::Post, move |mut request| {
+            let body = read_body(|buf| request.read(buf))?;
+            let unlock = serde_json::from_slice::<Unlock>(&body)?;
+            let seed = pin::unlock(&mut flash.lock().unwrap(), &unlock.pin)?;
+            let mut wait = mutex.0.lock().unwrap();
+            *wait = Some(seed);
+            mutex.1.notify_one();
+            let mut response = request.into_ok_response()?;
+            response.write("{\"success\":true}".as_bytes())?;
+            response.flush()?;
+            Ok(())
         })?;
     Ok(server)
 }
+
+fn read_body<E: Debug>(
+    mut read: impl FnMut(&mut [u8]) -> Result<usize, E>,
+) -> Result<Vec<u8>, HandlerError> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = read(&mut buf)?;
+        if n == 0 {
+            return Ok(body);
+        }
+        if body.len() + n > MAX_BODY_LEN {
+            return Err(HandlerError::new("body too long"));
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::core::events::Event as CoreEvent;
+use sphinx_key_common::topics as common_topics;
 use sphinx_signer::sphinx_glyph::topics;
 
 use anyhow::Result;
@@ -9,7 +10,7 @@ pub(crate) use sphinx_signer::root::MsgBytes;
 use std::sync::mpsc;
 use std::thread;
 
-pub const QOS: QoS = QoS::AtMostOnce;
+pub const QOS: QoS = QoS::AtLeastOnce;
 
 pub fn make_client(
     broker: &str,
@@ -50,6 +51,7 @@ pub fn make_client(
         info!("MQTT Listening for messages");
         let mut inflight = MsgBytes::new();
         let mut inflight_topic = "".to_string();
+        let mut inflight_sequence = None;
         while let Some(msg) = connection.next() {
             match msg {
                 Err(e) => match e.to_string().as_ref() {
@@ -74,15 +76,18 @@ pub fn make_client(
                     Event::Unsubscribed(_mes_id) => info!("RECEIVED Unsubscribed MESSAGE"),
                     Event::Published(_mes_id) => info!("RECEIVED Published MESSAGE"),
                     Event::Received(msg) => {
-                        let incoming_message: Option<(String, MsgBytes)> = match msg.details() {
+                        // (topic, payload, VLS sequence)
+                        let incoming_message = match msg.details() {
                             Details::Complete => {
                                 let mut buf = MsgBytes::new();
                                 buf.write(msg.data());
-                                msg.topic().map(|topic| (topic.to_string(), buf))
+                                let sequence = peek_sequence(msg.data());
+                                msg.topic().map(|topic| (topic.to_string(), buf, sequence))
                             }
                             Details::InitialChunk(_chunk_info) => {
                                 if let Some(topic) = msg.topic() {
                                     inflight_topic = topic.to_string();
+                                    inflight_sequence = peek_sequence(msg.data());
                                     inflight.write(msg.data());
                                     None
                                 } else {
@@ -92,9 +97,10 @@ pub fn make_client(
                             Details::SubsequentChunk(chunk_data) => {
                                 inflight.write(msg.data());
                                 if inflight.len() == chunk_data.total_data_size {
-                                    let ret = Some((inflight_topic, inflight));
+                                    let ret = Some((inflight_topic, inflight, inflight_sequence));
                                     inflight_topic = String::new();
                                     inflight = MsgBytes::new();
+                                    inflight_sequence = None;
                                     ret
                                 } else {
                                     None
@@ -102,9 +108,9 @@ pub fn make_client(
                             }
                         };
                         drop(msg);
-                        if let Some((topic, data)) = incoming_message {
+                        if let Some((topic, data, sequence)) = incoming_message {
                             if topic.ends_with(topics::VLS) {
-                                tx.send(CoreEvent::VlsMessage(data))
+                                tx.send(CoreEvent::VlsMessage(data, sequence))
                                     .expect("couldnt send Event::VlsMessage");
                             } else if topic.ends_with(topics::LSS_MSG)
                                 || topic.ends_with(topics::INIT_1_MSG)
@@ -118,6 +124,12 @@ pub fn make_client(
                             } else if topic.ends_with(topics::CONTROL) {
                                 tx.send(CoreEvent::Control(data.to_vec()))
                                     .expect("couldnt send Event::Control");
+                            } else if topic.ends_with(common_topics::EXT_CONTROL) {
+                                tx.send(CoreEvent::ExtControl(data.to_vec()))
+                                    .expect("couldnt send Event::ExtControl");
+                            } else if topic.ends_with(common_topics::OTA_CHUNK) {
+                                tx.send(CoreEvent::OtaChunk(data.to_vec()))
+                                    .expect("couldnt send Event::OtaChunk");
                             } else {
                                 log::warn!("unrecognized topic {}", topic);
                             }
@@ -133,6 +145,16 @@ pub fn make_client(
     Ok(client)
 }
 
+// VLS requests start with the serial request header:
+// magic (2 bytes) | sequence (2 bytes) | peer_id (33 bytes) | dbid (8 bytes)
+// the sequence is read up front so the event loop can spot QoS 1 redeliveries
+fn peek_sequence(data: &[u8]) -> Option<u16> {
+    if data.len() < 4 {
+        return None;
+    }
+    Some(u16::from_be_bytes([data[2], data[3]]))
+}
+
 // pub fn start_listening(
 //     client: EspMqttClient<ConnState<MessageImpl, EspError>>,
 //     mut connection: MqttConnection<Condvar, MessageImpl, EspError>,
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::peripheral;
 use esp_idf_svc::ipv4::Ipv4Addr;
@@ -8,16 +8,22 @@ use esp_idf_svc::wifi::{
     AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi,
 };
 use log::*;
+use sphinx_key_common::ext::WifiNetwork;
+use sphinx_key_common::setup::ScanEntry;
 use sphinx_signer::sphinx_glyph::control::Config;
 use std::fs;
 
 const SSID_FILE: &str = "/sdcard/ssid.txt";
 const PASS_FILE: &str = "/sdcard/password.txt";
+// attempts on one network before falling back to the next one
+const ATTEMPTS_PER_NETWORK: u8 = 3;
+// scans for the saved networks before giving up on all of them
+const SCAN_ROUNDS: u8 = 3;
 
 pub fn start_client(
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     default_nvs: EspDefaultNvsPartition,
-    config: &Config,
+    networks: &[WifiNetwork],
 ) -> Result<BlockingWifi<EspWifi<'static>>> {
     // let netif_stack = Arc::new(EspNetifStack::new()?);
     // let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
@@ -28,63 +34,109 @@ pub fn start_client(
         sysloop,
     )?;
 
-    let ssid = config.ssid.as_str();
-    let pass = config.pass.as_str();
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.into(),
-        password: pass.into(),
-        channel: None,
-        ..Default::default()
-    }))?;
-    info!("Wifi configured");
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    wifi.start()?;
+    info!("Wifi started");
 
-    loop {
-        match try_connection(&mut wifi) {
-            Ok(_) => break,
-            Err(e) => info!("error: {}, trying wifi connection again!", e),
+    for round in 1..=SCAN_ROUNDS {
+        for network in in_range(&mut wifi, networks) {
+            for attempt in 1..=ATTEMPTS_PER_NETWORK {
+                match try_connection(&mut wifi, network) {
+                    Ok(_) => {
+                        info!("wifi::start_client Ok(())");
+                        return Ok(wifi);
+                    }
+                    Err(e) => info!(
+                        "error: {}, attempt {} of {} on {}",
+                        e, attempt, ATTEMPTS_PER_NETWORK, network.ssid
+                    ),
+                }
+            }
         }
+        warn!(
+            "No saved network came up, round {} of {}",
+            round, SCAN_ROUNDS
+        );
     }
+    Err(anyhow!("could not connect to any saved wifi network"))
+}
 
-    // let status = wifi.get_status();
-    // println!("=> wifi STATUS {:?}", status);
-    // println!("=> is transitional? {:?}", status.is_transitional());
-    // if let Status(
-    //     ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip_settings))),
-    //     ApStatus::Stopped,
-    // ) = status
-    // {
-    //     info!("Wifi started!");
-    //     ping(&ip_settings)?;
-    // } else {
-    //     thread::sleep(Duration::from_secs(13));
-    //     // bail!("Unexpected Client Wifi status: {:?}", status);
-    //     return Err(anyhow::anyhow!(
-    //         "Unexpected Client Wifi status: {:?}",
-    //         status
-    //     ));
-    // }
-
-    info!("wifi::start_client Ok(())");
-
-    Ok(wifi)
+// the saved networks seen in a scan, by priority and then by signal strength.
+// If the scan fails, all of them by priority
+fn in_range<'a>(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    networks: &'a [WifiNetwork],
+) -> Vec<&'a WifiNetwork> {
+    let aps = match wifi.scan() {
+        Ok(aps) => aps,
+        Err(e) => {
+            warn!("wifi scan failed: {}", e);
+            let mut all: Vec<&WifiNetwork> = networks.iter().collect();
+            all.sort_by_key(|n| n.priority);
+            return all;
+        }
+    };
+    let mut found: Vec<(&WifiNetwork, i8)> = networks
+        .iter()
+        .filter_map(|n| {
+            aps.iter()
+                .filter(|ap| ap.ssid.as_str() == n.ssid)
+                .map(|ap| ap.signal_strength)
+                .max()
+                .map(|rssi| (n, rssi))
+        })
+        .collect();
+    found.sort_by(|a, b| a.0.priority.cmp(&b.0.priority).then(b.1.cmp(&a.1)));
+    info!(
+        "saved networks in range: {:?}",
+        found
+            .iter()
+            .map(|(n, rssi)| (&n.ssid, rssi))
+            .collect::<Vec<_>>()
+    );
+    found.into_iter().map(|(n, _)| n).collect()
 }
 
-fn try_connection(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
-    wifi.start()?;
-    info!("Wifi started");
-    wifi.connect()?;
+fn try_connection(wifi: &mut BlockingWifi<EspWifi<'static>>, network: &WifiNetwork) -> Result<()> {
+    let auth_method = if network.pass.is_empty() {
+        AuthMethod::None
+    } else {
+        AuthMethod::WPA2Personal
+    };
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: network.ssid.as_str().into(),
+        password: network.pass.as_str().into(),
+        auth_method,
+        channel: None,
+        ..Default::default()
+    }))?;
+    info!("Wifi configured for {}", network.ssid);
+    if let Err(e) = wifi.connect().and_then(|_| wifi.wait_netif_up()) {
+        let _ = wifi.disconnect();
+        return Err(e.into());
+    }
     info!("Wifi connected");
-    wifi.wait_netif_up()?;
-    info!("Wifi netif up");
     let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
     info!("Wifi DHCP info: {:?}", ip_info);
     Ok(())
 }
 
+// the network from the config server first, then the saved ones
+pub fn saved_networks(config: &Config, saved: Vec<WifiNetwork>) -> Vec<WifiNetwork> {
+    let mut networks = vec![WifiNetwork {
+        ssid: config.ssid.clone(),
+        pass: config.pass.clone(),
+        priority: 0,
+    }];
+    networks.extend(saved.into_iter().filter(|n| n.ssid != config.ssid));
+    networks
+}
+
+// also returns the networks in range, scanned before the access point comes up
 pub fn start_access_point(
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     default_nvs: EspDefaultNvsPartition,
-) -> Result<BlockingWifi<EspWifi<'static>>> {
+) -> Result<(BlockingWifi<EspWifi<'static>>, Vec<ScanEntry>)> {
     let sysloop = EspSystemEventLoop::take()?;
     // let netif_stack = Arc::new(EspNetifStack::new()?);
     // let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
@@ -92,6 +144,7 @@ pub fn start_access_point(
         EspWifi::new(modem, sysloop.clone(), Some(default_nvs))?,
         sysloop,
     )?;
+    let scan = scan_networks(&mut wifi);
 
     let ssid = match fs::read_to_string(SSID_FILE) {
         Ok(ssid) => ssid.trim().to_string(),
@@ -143,7 +196,36 @@ pub fn start_access_point(
     //     return Err(anyhow::anyhow!("Unexpected AP Wifi status: {:?}", status));
     // }
 
-    Ok(wifi)
+    Ok((wifi, scan))
+}
+
+fn scan_networks(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Vec<ScanEntry> {
+    let res = wifi
+        .set_configuration(&Configuration::Client(ClientConfiguration::default()))
+        .and_then(|_| wifi.start())
+        .and_then(|_| wifi.scan());
+    let _ = wifi.stop();
+    let mut aps = match res {
+        Ok(aps) => aps,
+        Err(e) => {
+            warn!("wifi scan failed: {}", e);
+            return Vec::new();
+        }
+    };
+    aps.sort_by(|a, b| b.signal_strength.cmp(&a.signal_strength));
+    let mut entries: Vec<ScanEntry> = Vec::new();
+    for ap in aps {
+        // the strongest access point of each network
+        if ap.ssid.is_empty() || entries.iter().any(|e| e.ssid == ap.ssid.as_str()) {
+            continue;
+        }
+        entries.push(ScanEntry {
+            ssid: ap.ssid.to_string(),
+            rssi: ap.signal_strength,
+            auth: format!("{:?}", ap.auth_method),
+        });
+    }
+    entries
 }
 
 fn _ping(ip_addr: Ipv4Addr) -> Result<()> {
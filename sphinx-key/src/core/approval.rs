@@ -0,0 +1,169 @@
+use crate::bitcoin::{Transaction, TxOut};
+use crate::button::Decision;
+
+use sphinx_key_common::ext::{Approval, ApprovalKind, ApprovalState};
+use sphinx_signer::approver::SphinxApprover;
+use sphinx_signer::lightning_signer::approver::Approve;
+use sphinx_signer::lightning_signer::invoice::{Invoice, InvoiceAttributes};
+use sphinx_signer::lightning_signer::lightning::ln::PaymentHash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// undecided approvals are rejected after this
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// an approval is only good for a retry this soon after
+const APPROVED_FOR: Duration = Duration::from_secs(10 * 60);
+// more than this and the oldest pending are dropped
+const MAX_PENDING: usize = 8;
+
+// what is over the velocity limit is held here instead of failing outright.
+// The payment fails the first time, and goes through when the node tries it
+// again after the owner approved it
+pub struct HumanApprover {
+    velocity: Arc<SphinxApprover>,
+    approvals: Arc<Mutex<Approvals>>,
+}
+
+impl HumanApprover {
+    pub fn new(velocity: Arc<SphinxApprover>, approvals: Arc<Mutex<Approvals>>) -> Self {
+        Self {
+            velocity,
+            approvals,
+        }
+    }
+    fn ask(&self, kind: ApprovalKind, id: String, amount_msat: u64) -> bool {
+        self.approvals.lock().unwrap().ask(kind, id, amount_msat)
+    }
+}
+
+impl Approve for HumanApprover {
+    fn approve_invoice(&self, invoice: &Invoice) -> bool {
+        if self.velocity.approve_invoice(invoice) {
+            return true;
+        }
+        let id = hex::encode(invoice.invoice_payment_hash().0);
+        self.ask(ApprovalKind::Invoice, id, invoice.invoice_amount_msat())
+    }
+    fn approve_keysend(&self, payment_hash: PaymentHash, amount_msat: u64) -> bool {
+        if self.velocity.approve_keysend(payment_hash, amount_msat) {
+            return true;
+        }
+        self.ask(
+            ApprovalKind::Keysend,
+            hex::encode(payment_hash.0),
+            amount_msat,
+        )
+    }
+    fn approve_onchain(&self, tx: &Transaction, prev_outs: &[TxOut], unknown: &[usize]) -> bool {
+        if self.velocity.approve_onchain(tx, prev_outs, unknown) {
+            return true;
+        }
+        // what leaves for outputs the wallet doesn't know
+        let amount_sat: u64 = unknown
+            .iter()
+            .filter_map(|i| tx.output.get(*i))
+            .map(|o| o.value)
+            .sum();
+        let id = tx.txid().to_string();
+        self.ask(ApprovalKind::Onchain, id, amount_sat * 1000)
+    }
+}
+
+// shared between the approver in the signer and the event loop, which
+// publishes the changes and hands over the decisions
+#[derive(Default)]
+pub struct Approvals {
+    pending: Vec<(Approval, Instant)>,
+    approved: Vec<(String, Instant)>,
+    // to publish
+    changed: Vec<Approval>,
+}
+
+impl Approvals {
+    fn ask(&mut self, kind: ApprovalKind, id: String, amount_msat: u64) -> bool {
+        if let Some(i) = self.approved.iter().position(|(a, _)| *a == id) {
+            self.approved.remove(i);
+            log::info!("approved {:?} {}", kind, id);
+            return true;
+        }
+        if self.pending.iter().any(|(a, _)| a.id == id) {
+            log::info!("still waiting on {:?} {}", kind, id);
+            return false;
+        }
+        if self.pending.len() >= MAX_PENDING {
+            let (oldest, _) = self.pending.remove(0);
+            self.resolve(oldest, ApprovalState::Expired);
+        }
+        log::warn!("{:?} {} for {} msat needs approval", kind, id, amount_msat);
+        let approval = Approval {
+            id,
+            kind,
+            amount_msat,
+            state: ApprovalState::Pending,
+        };
+        self.changed.push(approval.clone());
+        self.pending.push((approval, Instant::now()));
+        false
+    }
+
+    // by id, from a control msg
+    pub fn decide(&mut self, id: &str, approve: bool) -> Option<Approval> {
+        let i = self.pending.iter().position(|(a, _)| a.id == id)?;
+        let (approval, _) = self.pending.remove(i);
+        Some(self.resolve(approval, state(approve)))
+    }
+
+    // the button can't pick, so it only decides when one is waiting. With
+    // more than that it takes a signed Decide with the id
+    pub fn decide_only(&mut self, decision: Decision) -> Option<Approval> {
+        if self.pending.len() != 1 {
+            return None;
+        }
+        let (approval, _) = self.pending.remove(0);
+        Some(self.resolve(approval, state(decision == Decision::Approve)))
+    }
+
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.approved
+            .retain(|(_, at)| now.duration_since(*at) < APPROVED_FOR);
+        let (expired, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|(_, at)| now.duration_since(*at) >= APPROVAL_TIMEOUT);
+        self.pending = pending;
+        for (approval, _) in expired {
+            self.resolve(approval, ApprovalState::Expired);
+        }
+    }
+
+    pub fn list(&self) -> Vec<Approval> {
+        self.pending.iter().map(|(a, _)| a.clone()).collect()
+    }
+
+    pub fn take_changed(&mut self) -> Vec<Approval> {
+        std::mem::take(&mut self.changed)
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn resolve(&mut self, mut approval: Approval, state: ApprovalState) -> Approval {
+        log::info!("{:?} {} {:?}", approval.kind, approval.id, state);
+        approval.state = state;
+        if state == ApprovalState::Approved {
+            self.approved.push((approval.id.clone(), Instant::now()));
+        }
+        self.changed.push(approval.clone());
+        approval
+    }
+}
+
+fn state(approve: bool) -> ApprovalState {
+    if approve {
+        ApprovalState::Approved
+    } else {
+        ApprovalState::Rejected
+    }
+}
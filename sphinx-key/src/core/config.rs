@@ -1,10 +1,12 @@
+use crate::bitcoin::Network;
 use crate::conn;
+use crate::core::{pin, provision, FlashPersister};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::sync::{Arc, Condvar, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use esp_idf_svc::nvs::*;
 use esp_idf_svc::wifi::*;
@@ -13,9 +15,14 @@ use esp_idf_svc::hal::peripheral;
 
 use sphinx_crypter::chacha::{decrypt, PAYLOAD_LEN};
 use sphinx_crypter::ecdh::{derive_shared_secret_from_slice, PUBLIC_KEY_LEN};
-use sphinx_crypter::secp256k1::rand::thread_rng;
+use sphinx_crypter::secp256k1::rand::{thread_rng, RngCore};
 use sphinx_crypter::secp256k1::{PublicKey, Secp256k1, SecretKey};
 
+use sphinx_key_common::ext::WifiNetwork;
+use sphinx_key_common::mnemonic::{self, Mnemonic, CHALLENGE_LEN, ENTROPY_LEN};
+use sphinx_key_common::setup::{
+    DeviceStatus, GenerateRequest, GenerateResponse, MnemonicAnswer, MnemonicChallenge,
+};
 use sphinx_signer::sphinx_glyph::control::Config;
 // #[derive(Clone, Debug, Deserialize, Serialize)]
 // pub struct Config {
@@ -33,8 +40,15 @@ pub struct ConfigDTO {
     pub network: String,
     pub pubkey: Option<String>,
     pub seed: Option<String>, // encrypted (56 bytes)
+    // locks the seed in flash, see core/pin.rs
+    #[serde(default)]
+    pub pin: Option<String>,
 }
 
+// what the config server hands back: the seed if one was sent or
+// generated, and the PIN to lock it with
+pub type ConfigSeedPin = (Config, Option<[u8; 32]>, Option<String>);
+
 /*
 52.91.253.115:1883
 arp -a
@@ -43,9 +57,9 @@ arp -a
 pub fn start_wifi_client(
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     default_nvs: EspDefaultNvsPartition,
-    config: &Config,
+    networks: &[WifiNetwork],
 ) -> Result<BlockingWifi<EspWifi>> {
-    let wifi = conn::wifi::start_client(modem, default_nvs, config)?;
+    let wifi = conn::wifi::start_client(modem, default_nvs, networks)?;
     println!("CLIENT CONNECTED!!!!!! {:?}", wifi.is_connected());
     Ok(wifi)
 }
@@ -55,17 +69,95 @@ pub fn ecdh_keypair() -> (SecretKey, PublicKey) {
     s.generate_keypair(&mut thread_rng())
 }
 
-pub fn decrypt_seed(dto: ConfigDTO, sk1: SecretKey) -> Result<(Config, Option<[u8; 32]>)> {
+pub fn parse_network(network: &str) -> Option<Network> {
+    match network {
+        "bitcoin" => Some(Network::Bitcoin),
+        "mainnet" => Some(Network::Bitcoin),
+        "testnet" => Some(Network::Testnet),
+        "signet" => Some(Network::Signet),
+        "regtest" => Some(Network::Regtest),
+        _ => None,
+    }
+}
+
+// a seed made on the device, only handed over once its mnemonic is confirmed
+// or a backup of it has been sent out
+pub struct GeneratedSeed {
+    mnemonic: Mnemonic,
+    challenge: [usize; CHALLENGE_LEN],
+    confirmed: bool,
+    // the network the node pubkey was reported for
+    network: Option<String>,
+}
+
+impl GeneratedSeed {
+    pub fn generate() -> Self {
+        // straight from the hardware RNG, which is seeded by the radio that
+        // is already up for the access point
+        let mut entropy = [0u8; ENTROPY_LEN];
+        unsafe {
+            esp_idf_svc::sys::esp_fill_random(entropy.as_mut_ptr() as *mut _, ENTROPY_LEN);
+        }
+        let mut random = [0u8; CHALLENGE_LEN];
+        thread_rng().fill_bytes(&mut random);
+        Self {
+            mnemonic: mnemonic::generate(&entropy),
+            challenge: mnemonic::challenge(&random),
+            confirmed: false,
+            network: None,
+        }
+    }
+    pub fn challenge(&self) -> MnemonicChallenge {
+        MnemonicChallenge {
+            words: self.mnemonic.words().map(|w| w.to_string()).collect(),
+            challenge: self.challenge.to_vec(),
+        }
+    }
+    pub fn confirm(&mut self, answer: &MnemonicAnswer) -> bool {
+        let words: Vec<&str> = self.mnemonic.words().collect();
+        self.confirmed = answer.words.len() == CHALLENGE_LEN
+            && self
+                .challenge
+                .iter()
+                .zip(answer.words.iter())
+                .all(|(i, w)| words[*i] == w.trim().to_lowercase());
+        self.confirmed
+    }
+    // the backup is sealed to the caller's pubkey, see provision::seal
+    pub fn back_up(&mut self, req: &GenerateRequest) -> Result<GenerateResponse> {
+        let network = parse_network(&req.network)
+            .ok_or_else(|| anyhow!("unknown network {}", req.network))?;
+        let seed = mnemonic::seed(&self.mnemonic, "");
+        let backup = provision::seal(seed, &req.backup_pubkey)?;
+        let (node_pubkey, _) = sphinx_signer::derive_node_keys(&network, &seed);
+        self.confirmed = true;
+        self.network = Some(req.network.clone());
+        Ok(GenerateResponse {
+            node_pubkey: hex::encode(node_pubkey.serialize()),
+            backup,
+        })
+    }
+    pub fn seed(&self) -> Option<[u8; 32]> {
+        self.confirmed.then(|| mnemonic::seed(&self.mnemonic, ""))
+    }
+    // a config for another network would give the node a different pubkey
+    // than the one that was handed out
+    pub fn network_matches(&self, network: &str) -> bool {
+        self.network.as_ref().map_or(true, |n| n == network)
+    }
+}
+
+pub fn decrypt_seed(dto: ConfigDTO, sk1: SecretKey) -> Result<ConfigSeedPin> {
     let mut seed = None;
     if let Some(pubkey) = dto.pubkey {
         if let Some(seed_in) = dto.seed {
             let their_pk = hex::decode(pubkey)?;
-            let their_pk_bytes: [u8; PUBLIC_KEY_LEN] = their_pk[..PUBLIC_KEY_LEN].try_into()?;
+            let their_pk_bytes: [u8; PUBLIC_KEY_LEN] = their_pk.as_slice().try_into()?;
             let shared_secret =
                 derive_shared_secret_from_slice(their_pk_bytes, sk1.secret_bytes())?;
             // decrypt seed
             let cipher_seed = hex::decode(seed_in)?;
-            let cipher: [u8; PAYLOAD_LEN] = cipher_seed[..PAYLOAD_LEN].try_into()?;
+            let cipher: [u8; PAYLOAD_LEN] = cipher_seed.as_slice().try_into()?;
             seed = Some(decrypt(cipher, shared_secret)?);
         }
     }
@@ -77,21 +169,35 @@ pub fn decrypt_seed(dto: ConfigDTO, sk1: SecretKey) -> Result<(Config, Option<[u
             network: dto.network,
         },
         seed,
+        dto.pin,
     ))
 }
 
+// gives up after the timeout, if any
 pub fn start_config_server_and_wait(
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     default_nvs: EspDefaultNvsPartition,
+    flash: Arc<Mutex<FlashPersister>>,
     has_stored_seed: bool,
-) -> Result<(BlockingWifi<EspWifi<'static>>, Config, Option<[u8; 32]>)> {
+    timeout: Option<Duration>,
+) -> Result<(BlockingWifi<EspWifi<'static>>, ConfigSeedPin)> {
     let mutex = Arc::new((Mutex::new(None), Condvar::new()));
 
     #[allow(clippy::redundant_clone)]
     #[allow(unused_mut)]
-    let mut wifi = conn::wifi::start_access_point(modem, default_nvs.clone())?;
+    let (mut wifi, scan) = conn::wifi::start_access_point(modem, default_nvs.clone())?;
 
-    let httpd = conn::http::config_server(mutex.clone(), has_stored_seed)?;
+    let dns = wifi
+        .wifi()
+        .ap_netif()
+        .get_ip_info()
+        .map_err(anyhow::Error::from)
+        .and_then(|ip_info| conn::dns::CaptiveDns::start(ip_info.ip.octets().into()));
+    if let Err(e) = &dns {
+        log::warn!("no captive DNS: {:?}", e);
+    }
+    let status = device_status(&flash, has_stored_seed)?;
+    let httpd = conn::http::config_server(mutex.clone(), flash, scan, status)?;
     let mut wait = mutex.0.lock().unwrap();
     if has_stored_seed {
         log::info!("Waiting for wifi details from the phone!");
@@ -99,9 +205,12 @@ pub fn start_config_server_and_wait(
         log::info!("Waiting for seed and data from the phone!");
     }
 
-    let config_seed_tuple: &(Config, Option<[u8; 32]>) = loop {
+    let started = Instant::now();
+    let config_seed_tuple: &ConfigSeedPin = loop {
         if let Some(conf) = &*wait {
             break conf;
+        } else if timeout.map_or(false, |t| started.elapsed() > t) {
+            return Err(anyhow!("no config received"));
         } else {
             wait = mutex
                 .1
@@ -112,8 +221,56 @@ pub fn start_config_server_and_wait(
     };
 
     drop(httpd);
+    drop(dns);
     // drop(wifi);
     // thread::sleep(Duration::from_secs(1));
     println!("===> config! {:?}", config_seed_tuple.0);
-    Ok((wifi, config_seed_tuple.0.clone(), config_seed_tuple.1))
+    Ok((wifi, config_seed_tuple.clone()))
+}
+
+// the access point again, until the PIN comes in. The seed is only ever
+// in the clear in memory
+pub fn wait_for_pin(
+    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
+    default_nvs: EspDefaultNvsPartition,
+    flash: Arc<Mutex<FlashPersister>>,
+) -> Result<[u8; 32]> {
+    let mutex = Arc::new((Mutex::new(None), Condvar::new()));
+    let (wifi, _scan) = conn::wifi::start_access_point(modem, default_nvs)?;
+    let mut status = device_status(&flash, true)?;
+    status.locked = true;
+    let httpd = conn::http::unlock_server(mutex.clone(), flash.clone(), status)?;
+    log::info!("Waiting for the PIN!");
+
+    let mut wait = mutex.0.lock().unwrap();
+    let seed = loop {
+        if let Some(seed) = *wait {
+            break seed;
+        }
+        // too many wrong PINs
+        if !pin::is_locked(&flash.lock().unwrap()) {
+            return Err(anyhow!("the seed was wiped"));
+        }
+        wait = mutex
+            .1
+            .wait_timeout(wait, Duration::from_secs(1))
+            .unwrap()
+            .0;
+    };
+    drop(httpd);
+    drop(wifi);
+    Ok(seed)
+}
+
+fn device_status(flash: &Mutex<FlashPersister>, has_seed: bool) -> Result<DeviceStatus> {
+    let mut flash = flash.lock().unwrap();
+    let device_key = provision::device_key(&mut flash)?;
+    let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &device_key);
+    Ok(DeviceStatus {
+        firmware: env!("CARGO_PKG_VERSION").to_string(),
+        ota_version: flash.read_fw_version(),
+        has_seed,
+        pubkey: hex::encode(pubkey.serialize()),
+        locked: false,
+    })
 }
@@ -1,9 +1,13 @@
 use crate::bitcoin::Network;
+use crate::core::pin::SEALED_LEN;
 use crate::ID_LEN;
 use anyhow::{anyhow, Context, Result};
 use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
 use glyph::control::{Config, ControlPersist, Controller, FlashKey, Policy, Velocity};
 use glyph::ser::*;
+use sphinx_key_common::ext::{ButtonActions, ResetMode, WifiNetwork, MAX_WIFI_NETWORKS};
+use sphinx_key_common::led::DEFAULT_BRIGHTNESS;
+use sphinx_key_common::release::{KeySet, KEYSET_LEN};
 use sphinx_signer::sphinx_glyph as glyph;
 use std::convert::TryInto;
 use std::sync::{Arc, Mutex};
@@ -18,6 +22,29 @@ pub fn controller_from_seed(
     Controller::new_with_persister(sk, pk, flash)
 }
 
+// the lowest firmware version that can be installed without a signed downgrade
+const MIN_VERSION_KEY: &str = "minver";
+// the version of the image the factory app last flashed, so the running one
+const FW_VERSION_KEY: &str = "fwver";
+// the release keys, once rotated away from the compiled in ones
+const OTA_KEYS_KEY: &str = "otakeys";
+// the ECDH key provisioning files are sealed to, see core/provision.rs
+const DEVICE_KEY_KEY: &str = "devkey";
+// the saved wifi networks, as json
+const WIFI_KEY: &str = "wifis";
+const WIFI_BUF_LEN: usize = 2048;
+// the seed sealed with the PIN key, see core/pin.rs
+const SEALED_SEED_KEY: &str = "seedpin";
+const PIN_FAILURES_KEY: &str = "pinfails";
+// the last reset from the button, reported after the next connect
+const LAST_RESET_KEY: &str = "lastreset";
+// what the button gestures do, as json
+const BUTTON_KEY: &str = "button";
+// one byte, also read by the factory app
+const LED_BRIGHTNESS_KEY: &str = "ledbright";
+// set once the plaintext store from before encryption was migrated
+const STORE_MIGRATED_KEY: &str = "storemig";
+
 // EspDefaultNvsPartition
 pub struct FlashPersister(pub EspDefaultNvs);
 
@@ -26,6 +53,190 @@ impl FlashPersister {
         let store = EspDefaultNvs::new(nvs, "sphinx", true).expect("no storage");
         Self(store)
     }
+    fn read_u64(&self, key: &str) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        let existing = self
+            .0
+            .get_raw(key, &mut buf)?
+            .ok_or(anyhow!("no existing {}", key))?;
+        let r: [u8; 8] = existing.try_into()?;
+        Ok(u64::from_be_bytes(r))
+    }
+    pub fn read_min_version(&self) -> u64 {
+        self.read_u64(MIN_VERSION_KEY).unwrap_or(0)
+    }
+    pub fn read_fw_version(&self) -> Option<u64> {
+        self.read_u64(FW_VERSION_KEY).ok()
+    }
+    // once a newly flashed image has come up. Never lowered, not even by
+    // an authorized downgrade
+    pub fn raise_min_version(&mut self, version: u64) -> Result<()> {
+        let min = self.read_min_version().max(version);
+        self.0.set_raw(MIN_VERSION_KEY, &min.to_be_bytes()[..])?;
+        Ok(())
+    }
+    pub fn store_migrated(&self) -> bool {
+        matches!(
+            self.0.get_raw(STORE_MIGRATED_KEY, &mut [0u8; 1]),
+            Ok(Some(_))
+        )
+    }
+    pub fn set_store_migrated(&mut self) -> Result<()> {
+        self.0.set_raw(STORE_MIGRATED_KEY, &[1])?;
+        Ok(())
+    }
+    pub fn read_ota_keys(&self) -> KeySet {
+        let mut buf = [0u8; KEYSET_LEN];
+        match self.0.get_raw(OTA_KEYS_KEY, &mut buf) {
+            Ok(Some(b)) => KeySet::from_slice(b).unwrap_or_else(|| {
+                log::error!("corrupt ota keys, using the compiled in ones");
+                KeySet::compiled()
+            }),
+            _ => KeySet::compiled(),
+        }
+    }
+    pub fn write_ota_keys(&mut self, keys: &KeySet) -> Result<()> {
+        let (b, len) = keys.to_bytes();
+        self.0.set_raw(OTA_KEYS_KEY, &b[..len])?;
+        Ok(())
+    }
+    pub fn read_device_key(&self) -> Option<[u8; 32]> {
+        let mut buf = [0u8; 32];
+        let existing = self.0.get_raw(DEVICE_KEY_KEY, &mut buf).ok()??;
+        existing.try_into().ok()
+    }
+    pub fn write_device_key(&mut self, key: &[u8; 32]) -> Result<()> {
+        self.0.set_raw(DEVICE_KEY_KEY, &key[..])?;
+        Ok(())
+    }
+    // either in the clear, or PIN locked
+    pub fn has_seed(&self) -> bool {
+        self.read_seed().is_ok() || self.read_sealed_seed().is_some()
+    }
+    pub fn read_sealed_seed(&self) -> Option<[u8; SEALED_LEN]> {
+        let mut buf = [0u8; SEALED_LEN];
+        let existing = self.0.get_raw(SEALED_SEED_KEY, &mut buf).ok()??;
+        existing.try_into().ok()
+    }
+    pub fn write_sealed_seed(&mut self, sealed: &[u8; SEALED_LEN]) -> Result<()> {
+        self.0.set_raw(SEALED_SEED_KEY, &sealed[..])?;
+        Ok(())
+    }
+    pub fn remove_sealed_seed(&mut self) -> Result<()> {
+        self.0.remove(SEALED_SEED_KEY)?;
+        Ok(())
+    }
+    pub fn pin_failures(&self) -> u64 {
+        self.read_u64(PIN_FAILURES_KEY).unwrap_or(0)
+    }
+    pub fn set_pin_failures(&mut self, failures: u64) -> Result<()> {
+        self.0
+            .set_raw(PIN_FAILURES_KEY, &failures.to_be_bytes()[..])?;
+        Ok(())
+    }
+    pub fn remove_pin_failures(&mut self) -> Result<()> {
+        self.0.remove(PIN_FAILURES_KEY)?;
+        Ok(())
+    }
+    pub fn remove_id(&mut self) -> Result<()> {
+        self.0.remove(FlashKey::Id.as_str())?;
+        Ok(())
+    }
+    pub fn remove_velocity(&mut self) -> Result<()> {
+        self.0.remove(FlashKey::Velocity.as_str())?;
+        Ok(())
+    }
+    pub fn set_last_reset(&mut self, mode: ResetMode) -> Result<()> {
+        self.0
+            .set_raw(LAST_RESET_KEY, &serde_json::to_vec(&mode)?)?;
+        Ok(())
+    }
+    // read once, so it is only reported once
+    pub fn take_last_reset(&mut self) -> Option<ResetMode> {
+        let mut buf = [0u8; 16];
+        let mode = serde_json::from_slice(self.0.get_raw(LAST_RESET_KEY, &mut buf).ok()??).ok();
+        if let Err(e) = self.0.remove(LAST_RESET_KEY) {
+            log::error!("could not remove the last reset {:?}", e);
+        }
+        mode
+    }
+    pub fn read_button_actions(&self) -> ButtonActions {
+        let mut buf = [0u8; 256];
+        match self.0.get_raw(BUTTON_KEY, &mut buf) {
+            Ok(Some(b)) => serde_json::from_slice(b).unwrap_or_else(|_| {
+                log::error!("corrupt button actions, using the defaults");
+                ButtonActions::default()
+            }),
+            _ => ButtonActions::default(),
+        }
+    }
+    pub fn write_button_actions(&mut self, actions: &ButtonActions) -> Result<()> {
+        if !actions.is_valid() {
+            return Err(anyhow!("a reset is only allowed on a long press"));
+        }
+        self.0.set_raw(BUTTON_KEY, &serde_json::to_vec(actions)?)?;
+        Ok(())
+    }
+    pub fn read_led_brightness(&self) -> u8 {
+        let mut buf = [0u8; 1];
+        match self.0.get_raw(LED_BRIGHTNESS_KEY, &mut buf) {
+            Ok(Some(&[b])) => b,
+            _ => DEFAULT_BRIGHTNESS,
+        }
+    }
+    pub fn write_led_brightness(&mut self, brightness: u8) -> Result<()> {
+        if brightness == 0 {
+            return Err(anyhow!("the error codes wouldn't show with the LED off"));
+        }
+        self.0.set_raw(LED_BRIGHTNESS_KEY, &[brightness])?;
+        Ok(())
+    }
+    pub fn read_wifi_networks(&self) -> Vec<WifiNetwork> {
+        let mut buf = vec![0u8; WIFI_BUF_LEN];
+        match self.0.get_raw(WIFI_KEY, &mut buf) {
+            Ok(Some(b)) => serde_json::from_slice(b).unwrap_or_else(|_| {
+                log::error!("corrupt wifi networks, ignoring them");
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        }
+    }
+    fn write_wifi_networks(&mut self, networks: &[WifiNetwork]) -> Result<()> {
+        let bytes = serde_json::to_vec(networks)?;
+        if bytes.len() > WIFI_BUF_LEN {
+            return Err(anyhow!("too many wifi networks"));
+        }
+        self.0.set_raw(WIFI_KEY, &bytes)?;
+        Ok(())
+    }
+    // replaces a saved network with the same ssid
+    pub fn add_wifi_network(&mut self, network: WifiNetwork) -> Result<Vec<WifiNetwork>> {
+        if !network.is_valid() {
+            return Err(anyhow!("invalid ssid or password"));
+        }
+        let mut networks = self.read_wifi_networks();
+        networks.retain(|n| n.ssid != network.ssid);
+        if networks.len() >= MAX_WIFI_NETWORKS {
+            return Err(anyhow!("at most {} wifi networks", MAX_WIFI_NETWORKS));
+        }
+        networks.push(network);
+        self.write_wifi_networks(&networks)?;
+        Ok(networks)
+    }
+    pub fn remove_wifi_networks(&mut self) -> Result<()> {
+        self.0.remove(WIFI_KEY)?;
+        Ok(())
+    }
+    pub fn remove_wifi_network(&mut self, ssid: &str) -> Result<Vec<WifiNetwork>> {
+        let mut networks = self.read_wifi_networks();
+        let before = networks.len();
+        networks.retain(|n| n.ssid != ssid);
+        if networks.len() == before {
+            return Err(anyhow!("no saved wifi network {}", ssid));
+        }
+        self.write_wifi_networks(&networks)?;
+        Ok(networks)
+    }
 }
 
 impl ControlPersist for FlashPersister {
@@ -1,8 +1,17 @@
+use crate::button::Decision;
 use crate::conn::mqtt::QOS;
-use crate::core::lss;
-use crate::ota::{update_sphinx_key, validate_ota_message};
+use crate::core::approval::{Approvals, HumanApprover};
+use crate::core::ext::handle_ext_control;
+use crate::core::store::{store_key, EncryptedKVVStore};
+use crate::core::{health, heap, lss, reset, restarts, FlashPersister};
+use crate::led::restart_with_error;
+use crate::ota::{
+    is_mqtt_update, mark_running_slot_valid, progress_due, update_sphinx_key, validate_ota_message,
+    MqttUpdate,
+};
 use crate::status::Status;
 
+use crate::bitcoin::hashes::{sha256, Hash};
 use crate::bitcoin::Network;
 use crate::conn::mqtt::MsgBytes;
 use glyph::control::{Config, ControlMessage, ControlResponse, Controller, Policy, Velocity};
@@ -10,16 +19,22 @@ use glyph::error::Error as GlyphError;
 use glyph::ser::{serialize_controlresponse, ByteBuf};
 use glyph::topics;
 use lss_connector::secp256k1::PublicKey;
+use sphinx_key_common::ext::{ExtResponse, HeapPressure, RestartReason};
+use sphinx_key_common::hello::{caps, Hello};
+use sphinx_key_common::led::ErrorCode;
+use sphinx_key_common::ota::{self as ota_proto, Stage};
+use sphinx_key_common::topics as common_topics;
 use sphinx_signer::approver::SphinxApprover;
 use sphinx_signer::kvv::{fs::FsKVVStore, CloudKVVStore, KVVPersister, RmpFormat};
 // use sphinx_signer::kvv::{JsonFormat, MemoryKVVStore};
 use sphinx_signer::lightning_signer::persist::Persist;
 use sphinx_signer::root::VlsHandlerError;
 use sphinx_signer::sphinx_glyph as glyph;
-use sphinx_signer::{self, Handler, RootHandler, WarningPositiveApprover};
+use sphinx_signer::{self, Handler, RootHandler};
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use esp_idf_svc::mqtt::client::*;
 use esp_idf_svc::sys::EspError;
@@ -27,12 +42,41 @@ use esp_idf_svc::sys::EspError;
 pub enum Event {
     Connected,
     Disconnected,
-    VlsMessage(MsgBytes),
+    // the sequence is peeked from the request header, if present
+    VlsMessage(MsgBytes, Option<u16>),
     LssMessage(Vec<u8>),
     Control(Vec<u8>),
+    ExtControl(Vec<u8>),
+    OtaChunk(Vec<u8>),
 }
 
 pub const ROOT_STORE: &str = "/sdcard/store";
+// how often the approvals and the heap are checked on
+const APPROVAL_TICK: Duration = Duration::from_secs(1);
+
+// the last reply published for a request, replayed if
+// the broker redelivers the same request (QoS 1)
+struct CachedReply<K> {
+    key: K,
+    topic: String,
+    payload: Vec<u8>,
+}
+
+impl<K: PartialEq> CachedReply<K> {
+    fn new(key: K, topic: &str, payload: &[u8]) -> Self {
+        Self {
+            key,
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+        }
+    }
+    fn matches(cached: &Option<Self>, key: &K) -> Option<(String, Vec<u8>)> {
+        cached
+            .as_ref()
+            .filter(|c| &c.key == key)
+            .map(|c| (c.topic.clone(), c.payload.clone()))
+    }
+}
 
 fn mqtt_sub(
     mqtt: &mut EspMqttClient<ConnState<MessageImpl, EspError>>,
@@ -58,6 +102,33 @@ fn mqtt_pub(
         .expect("could not MQTT publish");
 }
 
+fn pub_progress(
+    mqtt: &mut EspMqttClient<ConnState<MessageImpl, EspError>>,
+    client_id: &str,
+    progress: ota_proto::Progress,
+) {
+    log::info!(
+        "OTA {:?}: {} of {} bytes",
+        progress.stage,
+        progress.received,
+        progress.total
+    );
+    mqtt_pub(
+        mqtt,
+        client_id,
+        common_topics::OTA_PROGRESS,
+        &progress.to_bytes(),
+    );
+}
+
+// everything this firmware can do, the broker checks for what it needs
+const CAPS: [&str; 4] = [caps::LSS, caps::QOS1, caps::TELEMETRY, caps::OTA_MQTT];
+
+fn hello_payload(ota_version: Option<u64>) -> Vec<u8> {
+    let hello = Hello::new(&CAPS, Some(env!("CARGO_PKG_VERSION"))).with_ota_version(ota_version);
+    serde_json::to_vec(&hello).expect("could not serialize HELLO")
+}
+
 // the main event loop
 #[cfg(not(feature = "pingpong"))]
 #[allow(clippy::too_many_arguments)]
@@ -72,21 +143,56 @@ pub fn make_event_loop(
     policy: &Policy,
     velocity: &Option<Velocity>,
     mut ctrlr: Controller,
+    flash: Arc<Mutex<FlashPersister>>,
     signer_id: &[u8; 16],
     node_id: &PublicKey,
+    decisions: Arc<Mutex<mpsc::Receiver<Decision>>>,
 ) {
     let client_id = hex::encode(signer_id);
+    let ota_version = flash.lock().unwrap().read_fw_version();
 
     while let Ok(event) = rx.recv() {
         log::info!("BROKER IP AND PORT: {}", config.broker);
         // wait for a Connection first.
         if let Event::Connected = event {
             mqtt_sub(&mut mqtt, &client_id, topics::SIGNER_SUBS);
+            mqtt_sub(&mut mqtt, &client_id, &common_topics::SIGNER_SUBS);
             break;
         }
     }
 
-    let kvv_store = FsKVVStore::new(ROOT_STORE, *signer_id, None);
+    match health::sweep(ROOT_STORE) {
+        Ok(0) => (),
+        Ok(n) => log::warn!("quarantined {} half written files", n),
+        Err(e) => log::error!("failed to sweep the store {:?}", e),
+    }
+    let fs_store = FsKVVStore::new(ROOT_STORE, *signer_id, None);
+    let kvv_store = EncryptedKVVStore::new(fs_store, store_key(&seed));
+    // only ever once, after that a value in the clear is one planted on the
+    // card, and the check sets it aside
+    if !flash.lock().unwrap().store_migrated() {
+        match kvv_store.migrate() {
+            Ok(n) => {
+                log::info!("encrypted {} values left from before", n);
+                if let Err(e) = flash.lock().unwrap().set_store_migrated() {
+                    log::error!("failed to mark the store migrated {:?}", e);
+                }
+            }
+            Err(e) => log::error!("failed to encrypt the old state {:?}", e),
+        }
+    }
+    match kvv_store.check(health::quarantine) {
+        Ok(0) => (),
+        Ok(n) => log::warn!("quarantined {} unreadable entries, LSS has them", n),
+        Err(e) => log::error!("failed to check the store {:?}", e),
+    }
+    match health::compact(ROOT_STORE, &kvv_store) {
+        Ok(0) => (),
+        Ok(n) => log::info!("compacted away {} old files", n),
+        Err(e) => log::error!("failed to compact the store {:?}", e),
+    }
+    // for putting back what LSS has newer
+    let store = kvv_store.clone();
     //let kvv_store = MemoryKVVStore::new([0xcc; 16]);
     let fs_persister = KVVPersister(CloudKVVStore::new(kvv_store), RmpFormat);
 
@@ -122,44 +228,164 @@ pub fn make_event_loop(
         persister,
     )
     .expect("failed to init signer");
-    rhb = rhb.approver(Arc::new(WarningPositiveApprover()));
+    // over the velocity limit, the owner is asked
+    let approvals = Arc::new(Mutex::new(Approvals::default()));
+    rhb = rhb.approver(Arc::new(HumanApprover::new(
+        approver.clone(),
+        approvals.clone(),
+    )));
 
     thread::sleep(std::time::Duration::from_secs(1));
     // send the initial HELLO
-    mqtt_pub(&mut mqtt, &client_id, topics::HELLO, &[]);
+    mqtt_pub(
+        &mut mqtt,
+        &client_id,
+        topics::HELLO,
+        &hello_payload(ota_version),
+    );
+    // the button has no broker to report to, so it is done here
+    let last_reset = flash.lock().unwrap().take_last_reset();
+    if let Some(mode) = last_reset {
+        let bytes = serde_json::to_vec(&ExtResponse::Reset(mode)).expect("failed to serialize");
+        mqtt_pub(
+            &mut mqtt,
+            &client_id,
+            common_topics::EXT_CONTROL_RES,
+            &bytes,
+        );
+    }
 
-    let (root_handler, lss_signer) = match lss::init_lss(signer_id, &rx, rhb, &mut mqtt) {
+    // kept from before this boot, and the boots before that one if the
+    // signer didn't make it this far
+    for restart in restarts::take() {
+        let bytes = serde_json::to_vec(&restart).expect("failed to serialize Restart");
+        mqtt_pub(&mut mqtt, &client_id, common_topics::RESTART, &bytes);
+    }
+
+    let (root_handler, lss_signer) = match lss::init_lss(signer_id, &rx, rhb, &mut mqtt, &store) {
         Ok(rl) => rl,
         Err(e) => {
             log::error!("failed to init lss {:?}", e);
-            unsafe { esp_idf_svc::sys::esp_restart() };
+            let context = e.to_string();
+            restart_with_error(ErrorCode::Lss, &led_tx, RestartReason::LssInit, &context);
         }
     };
 
+    // connected and LSS is in sync, so the factory app can stop counting boots.
+    // Only now is a new image's version the floor for the next update
+    match mark_running_slot_valid() {
+        Ok(true) => {
+            if let Some(version) = ota_version {
+                log::info!("version {} came up, the minimum from now on", version);
+                if let Err(e) = flash.lock().unwrap().raise_min_version(version) {
+                    log::error!("failed to raise the minimum version {:?}", e);
+                }
+            }
+        }
+        Ok(false) => (),
+        Err(e) => log::error!("failed to mark the running firmware valid {:?}", e),
+    }
+
     // store the previous msgs processed, for LSS last step
     let mut msgs: Option<(Vec<u8>, [u8; 32])> = None;
 
+    // replies already published, keyed by VLS sequence or by msg hash
+    let mut last_vls: Option<CachedReply<u16>> = None;
+    let mut last_lss: Option<CachedReply<sha256::Hash>> = None;
+    let mut last_control: Option<CachedReply<sha256::Hash>> = None;
+    let mut last_ext: Option<CachedReply<sha256::Hash>> = None;
+
+    // armed by an OTA control msg, then fed chunks by the broker
+    let mut ota_update: Option<MqttUpdate> = None;
+
     // signing loop
     log::info!("=> starting the main signing loop...");
     let flash_db = ctrlr.persister();
     let mut expected_sequence = None;
     let mut current_status = Status::ConnectingToMqtt;
-    while let Ok(event) = rx.recv() {
-        check_memory();
+    let mut pressure = HeapPressure::Normal;
+    // a VLS msg put off while low on memory
+    let mut deferred: Option<(MsgBytes, Option<u16>)> = None;
+    loop {
+        // a VLS msg put off goes first once there is memory for it, unless a
+        // later one was handled since, which the broker only sends after it
+        let event = match deferred.take() {
+            Some((_, Some(seq))) if expected_sequence.is_some_and(|e| seq < e) => {
+                log::info!("dropping the VLS msg {} put off, already handled", seq);
+                continue;
+            }
+            Some((msg_bytes, seq)) if heap::pressure() == HeapPressure::Normal => {
+                log::info!("enough memory again for the VLS msg put off");
+                Ok(Event::VlsMessage(msg_bytes, seq))
+            }
+            held => {
+                deferred = held;
+                // wakes up now and then for the approvals, even with no msgs
+                rx.recv_timeout(APPROVAL_TICK)
+            }
+        };
+        let waiting = tick_approvals(&approvals, &decisions, &mut mqtt, &client_id);
+        if waiting {
+            current_status = update_led(current_status, Status::Approving, &led_tx);
+        } else if current_status == Status::Approving {
+            current_status = update_led(current_status, Status::Connected, &led_tx);
+        }
+        if heap::pressure() != pressure {
+            let warning = heap::warning();
+            pressure = warning.pressure;
+            let bytes = serde_json::to_vec(&warning).expect("failed to serialize HeapWarning");
+            mqtt_pub(&mut mqtt, &client_id, common_topics::HEAP, &bytes);
+            if pressure > HeapPressure::Normal {
+                let _ = led_tx.send(Status::Error(ErrorCode::Memory));
+            }
+        }
+        // with no LSS commit in flight, and none started since the VLS msgs
+        // wait, a restart here loses nothing
+        if pressure == HeapPressure::Hard && msgs.is_none() {
+            let w = heap::warning();
+            let context = format!("{} free, largest block {}", w.free, w.largest_block);
+            let reason = RestartReason::OutOfMemory;
+            restart_with_error(ErrorCode::Memory, &led_tx, reason, &context);
+        }
+        let event = match event {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
         match event {
             Event::Connected => {
                 log::info!("GOT A Event::Connected msg!");
                 mqtt_sub(&mut mqtt, &client_id, topics::SIGNER_SUBS);
+                mqtt_sub(&mut mqtt, &client_id, &common_topics::SIGNER_SUBS);
                 thread::sleep(std::time::Duration::from_secs(1));
                 // send the initial HELLO again
-                mqtt_pub(&mut mqtt, &client_id, topics::HELLO, &[]);
+                mqtt_pub(
+                    &mut mqtt,
+                    &client_id,
+                    topics::HELLO,
+                    &hello_payload(ota_version),
+                );
                 current_status = update_led(current_status, Status::Connected, &led_tx);
             }
             Event::Disconnected => {
                 log::info!("GOT A Event::Disconnected msg!");
-                unsafe { esp_idf_svc::sys::esp_restart() };
+                restarts::restart(RestartReason::Disconnected, "from the broker");
             }
-            Event::VlsMessage(msg_bytes) => {
+            Event::VlsMessage(msg_bytes, peeked_sequence) => {
+                if let Some(seq) = peeked_sequence {
+                    if let Some((topic, payload)) = CachedReply::matches(&last_vls, &seq) {
+                        log::info!("redelivered VLS msg {}, replaying {}", seq, topic);
+                        mqtt_pub(&mut mqtt, &client_id, &topic, &payload);
+                        continue;
+                    }
+                }
+                if pressure > HeapPressure::Normal {
+                    log::warn!("low on memory, putting off the VLS msg");
+                    deferred = Some((msg_bytes, peeked_sequence));
+                    continue;
+                }
+                // this one supersedes any put off before it
+                deferred = None;
                 current_status = update_led(current_status, Status::Signing, &led_tx);
                 let state1 = approver.control().get_state();
                 match sphinx_signer::root::handle_with_lss(
@@ -173,14 +399,18 @@ pub fn make_event_loop(
                         if let Some(server_hmac) = server_hmac_opt {
                             // muts! send LSS first!
                             mqtt_pub(&mut mqtt, &client_id, topics::LSS_RES, &lss_b);
+                            last_vls = Some(CachedReply::new(sequence, topics::LSS_RES, &lss_b));
                             msgs = Some((vls_b, server_hmac));
                         } else {
                             // no muts, respond directly back!
                             mqtt_pub(&mut mqtt, &client_id, topics::VLS_RES, &vls_b);
+                            last_vls = Some(CachedReply::new(sequence, topics::VLS_RES, &vls_b));
                             // and commit
                             if let Err(e) = root_handler.node().get_persister().commit() {
                                 log::error!("LOCAL COMMIT ERROR! {:?}", e);
-                                unsafe { esp_idf_svc::sys::esp_restart() };
+                                let context = format!("{:?}", e);
+                                let reason = RestartReason::CommitFailed;
+                                restart_with_error(ErrorCode::SdCard, &led_tx, reason, &context);
                             }
                         }
                         expected_sequence = Some(sequence + 1);
@@ -192,8 +422,8 @@ pub fn make_event_loop(
                                 current,
                                 expected
                             );
-                            log::info!("restarting esp!");
-                            unsafe { esp_idf_svc::sys::esp_restart() };
+                            let context = format!("got {} expected {}", current, expected);
+                            restarts::restart(RestartReason::BadSequence, &context);
                         }
                         _ => {
                             let err_msg = GlyphError::new(1, &e.to_string());
@@ -213,26 +443,42 @@ pub fn make_event_loop(
                 }
             }
             Event::LssMessage(msg_bytes) => {
+                let msg_hash = sha256::Hash::hash(&msg_bytes);
+                if let Some((topic, payload)) = CachedReply::matches(&last_lss, &msg_hash) {
+                    log::info!("redelivered LSS msg, replaying {}", topic);
+                    mqtt_pub(&mut mqtt, &client_id, &topic, &payload);
+                    continue;
+                }
                 match lss::handle_lss_msg(&msg_bytes, msgs, &lss_signer) {
                     Ok((ret_topic, bytes)) => {
                         // set msgs back to None
                         msgs = None;
                         mqtt_pub(&mut mqtt, &client_id, &ret_topic, &bytes);
+                        last_lss = Some(CachedReply::new(msg_hash, &ret_topic, &bytes));
                         if ret_topic == topics::VLS_RES {
+                            // the VLS request is answered now, replay the final reply
+                            if let Some(ref mut cached) = last_vls {
+                                cached.topic = ret_topic.clone();
+                                cached.payload = bytes.clone();
+                            }
                             // and commit
                             if let Err(e) = root_handler.node().get_persister().commit() {
                                 log::error!("LOCAL COMMIT ERROR AFTER LSS! {:?}", e);
-                                unsafe { esp_idf_svc::sys::esp_restart() };
+                                let context = format!("after LSS {:?}", e);
+                                let reason = RestartReason::CommitFailed;
+                                restart_with_error(ErrorCode::SdCard, &led_tx, reason, &context);
                             }
                         }
                         if ret_topic == topics::LSS_CONFLICT_RES {
                             log::error!("LSS PUT CONFLICT! RESTART...");
-                            unsafe { esp_idf_svc::sys::esp_restart() };
+                            let reason = RestartReason::LssConflict;
+                            restart_with_error(ErrorCode::Lss, &led_tx, reason, "put conflict");
                         }
                     }
                     Err(e) => {
                         log::error!("LSS MESSAGE FAILED!");
                         log::error!("{}", &e.to_string());
+                        let _ = led_tx.send(Status::Error(ErrorCode::Lss));
                         msgs = None;
                         let err_msg = GlyphError::new(1, &e.to_string());
                         mqtt_pub(&mut mqtt, &client_id, topics::ERROR, &err_msg.to_vec()[..]);
@@ -241,24 +487,151 @@ pub fn make_event_loop(
             }
             Event::Control(ref msg_bytes) => {
                 log::info!("GOT A CONTROL MSG");
+                let msg_hash = sha256::Hash::hash(msg_bytes);
+                if let Some((topic, payload)) = CachedReply::matches(&last_control, &msg_hash) {
+                    // the nonce was already used, so don't handle it again
+                    log::info!("redelivered CONTROL msg, replaying {}", topic);
+                    mqtt_pub(&mut mqtt, &client_id, &topic, &payload);
+                    continue;
+                }
                 let cres = ctrlr.handle(msg_bytes);
-                let res = handle_control_response(&root_handler, &approver, cres, led_tx.clone());
+                let res =
+                    handle_control_response(&root_handler, &approver, &flash, cres, led_tx.clone());
                 let mut bb = ByteBuf::new();
                 serialize_controlresponse(&mut bb, &res).expect("failed serialize_lssresponse");
                 mqtt_pub(&mut mqtt, &client_id, topics::CONTROL_RES, bb.as_slice());
+                last_control = Some(CachedReply::new(
+                    msg_hash,
+                    topics::CONTROL_RES,
+                    bb.as_slice(),
+                ));
                 if let ControlResponse::OtaConfirm(ref params) = res {
-                    if let Err(e) = update_sphinx_key(params) {
+                    if is_mqtt_update(params) {
+                        match MqttUpdate::start(params) {
+                            Ok(u) => ota_update = Some(u),
+                            Err(e) => {
+                                log::error!("OTA update failed {:?}", e.to_string());
+                                let _ = led_tx.send(Status::Error(ErrorCode::Ota));
+                            }
+                        }
+                    } else if let Err(e) =
+                        update_sphinx_key(params, &mut |p| pub_progress(&mut mqtt, &client_id, p))
+                    {
                         log::error!("OTA update failed {:?}", e.to_string());
+                        let _ = led_tx.send(Status::Error(ErrorCode::Ota));
                     } else {
                         log::info!("OTA flow complete, restarting esp...");
-                        unsafe { esp_idf_svc::sys::esp_restart() };
+                        let context = format!("installed version {}", params.version);
+                        restarts::restart(RestartReason::Ota, &context);
+                    }
+                }
+            }
+            Event::ExtControl(ref msg_bytes) => {
+                log::info!("GOT AN EXT CONTROL MSG");
+                let msg_hash = sha256::Hash::hash(msg_bytes);
+                if let Some((topic, payload)) = CachedReply::matches(&last_ext, &msg_hash) {
+                    log::info!("redelivered EXT CONTROL msg, replaying {}", topic);
+                    mqtt_pub(&mut mqtt, &client_id, &topic, &payload);
+                    continue;
+                }
+                let res = handle_ext_control(&ctrlr.pubkey(), &flash, &approvals, &seed, msg_bytes);
+                let bytes = serde_json::to_vec(&res).expect("failed to serialize ExtResponse");
+                mqtt_pub(
+                    &mut mqtt,
+                    &client_id,
+                    common_topics::EXT_CONTROL_RES,
+                    &bytes,
+                );
+                last_ext = Some(CachedReply::new(
+                    msg_hash,
+                    common_topics::EXT_CONTROL_RES,
+                    &bytes,
+                ));
+                match res {
+                    ExtResponse::Reset(mode) => reset::confirm_and_restart(mode, &led_tx),
+                    ExtResponse::Brightness(b) => led_tx.send(Status::Brightness(b)).unwrap(),
+                    _ => (),
+                }
+            }
+            Event::OtaChunk(ref chunk) => {
+                let Some(ref mut update) = ota_update else {
+                    log::warn!("OTA chunk but no update was launched");
+                    // an empty ack tells the broker to stop
+                    mqtt_pub(&mut mqtt, &client_id, common_topics::OTA_CHUNK_RES, &[]);
+                    continue;
+                };
+                let before = update.progress(Stage::Receiving).received;
+                let next = match update.write_chunk(chunk) {
+                    Ok(next) => next,
+                    Err(e) => {
+                        log::error!("OTA chunk failed {:?}", e.to_string());
+                        let _ = led_tx.send(Status::Error(ErrorCode::Ota));
+                        let failed = update.progress(Stage::Failed);
+                        mqtt_pub(&mut mqtt, &client_id, common_topics::OTA_CHUNK_RES, &[]);
+                        pub_progress(&mut mqtt, &client_id, failed);
+                        ota_update = None;
+                        continue;
+                    }
+                };
+                mqtt_pub(
+                    &mut mqtt,
+                    &client_id,
+                    common_topics::OTA_CHUNK_RES,
+                    &ota_proto::ack(next),
+                );
+                if !update.is_complete() {
+                    if progress_due(before, next) {
+                        let progress = update.progress(Stage::Receiving);
+                        pub_progress(&mut mqtt, &client_id, progress);
                     }
+                    continue;
+                }
+                let update = ota_update.take().unwrap();
+                let version = update.version();
+                pub_progress(&mut mqtt, &client_id, update.progress(Stage::Verifying));
+                let failed = update.progress(Stage::Failed);
+                if let Err(e) = update.finish() {
+                    log::error!("OTA update failed {:?}", e.to_string());
+                    let _ = led_tx.send(Status::Error(ErrorCode::Ota));
+                    pub_progress(&mut mqtt, &client_id, failed);
+                } else {
+                    let done = ota_proto::Progress::new(Stage::Installing, next, next);
+                    pub_progress(&mut mqtt, &client_id, done);
+                    log::info!("OTA flow complete, restarting esp...");
+                    let context = format!("installed version {}", version);
+                    restarts::restart(RestartReason::Ota, &context);
                 }
             }
         }
     }
 }
 
+// hands the button's decisions to the one approval, expires the old ones
+// and publishes what changed. True while any are still waiting
+fn tick_approvals(
+    approvals: &Mutex<Approvals>,
+    decisions: &Mutex<mpsc::Receiver<Decision>>,
+    mqtt: &mut EspMqttClient<ConnState<MessageImpl, EspError>>,
+    client_id: &str,
+) -> bool {
+    let mut approvals = approvals.lock().unwrap();
+    while let Ok(decision) = decisions.lock().unwrap().try_recv() {
+        if approvals.decide_only(decision).is_none() {
+            log::info!(
+                "the {:?} needs exactly one waiting, not {}",
+                decision,
+                approvals.list().len()
+            );
+        }
+    }
+    approvals.expire();
+    for approval in approvals.take_changed() {
+        let bytes = serde_json::to_vec(&approval).expect("failed to serialize Approval");
+        mqtt_pub(mqtt, client_id, common_topics::APPROVAL, &bytes);
+    }
+    approvals.is_waiting()
+}
+
 fn update_led(current: Status, new: Status, led_tx: &mpsc::Sender<Status>) -> Status {
     if current != new {
         led_tx.send(new).unwrap();
@@ -268,35 +641,10 @@ fn update_led(current: Status, new: Status, led_tx: &mpsc::Sender<Status>) -> St
     }
 }
 
-pub(crate) fn _restart_esp_if_memory_low() {
-    unsafe {
-        let size = esp_idf_svc::sys::heap_caps_get_free_size(4);
-        let block = esp_idf_svc::sys::heap_caps_get_largest_free_block(4);
-        let threshold = 25000;
-        log::info!(
-            "Available DRAM: {}, Max block: {}, Restart Threshold: {}",
-            size,
-            block,
-            threshold
-        );
-        if block < threshold {
-            log::info!("Restarting esp!");
-            esp_idf_svc::sys::esp_restart();
-        }
-    }
-}
-
-pub(crate) fn check_memory() {
-    unsafe {
-        let size = esp_idf_svc::sys::heap_caps_get_free_size(4);
-        let block = esp_idf_svc::sys::heap_caps_get_largest_free_block(4);
-        log::info!("CHECK: Available DRAM: {}, Max block: {}", size, block,);
-    }
-}
-
 fn handle_control_response(
     root_handler: &RootHandler,
     approver: &SphinxApprover,
+    flash: &Mutex<FlashPersister>,
     cres: anyhow::Result<(ControlMessage, ControlResponse)>,
     led_tx: mpsc::Sender<Status>,
 ) -> ControlResponse {
@@ -330,8 +678,13 @@ fn handle_control_response(
                     }
                 }
                 ControlMessage::Ota(ref params) => {
-                    if let Err(e) = validate_ota_message(params) {
+                    let flash = flash.lock().unwrap();
+                    let min_version = flash.read_min_version();
+                    let keys = flash.read_ota_keys();
+                    drop(flash);
+                    if let Err(e) = validate_ota_message(params, min_version, &keys) {
                         log::error!("OTA update cannot launch {:?}", e.to_string());
+                        let _ = led_tx.send(Status::Error(ErrorCode::Ota));
                         control_res =
                             ControlResponse::Error(format!("OTA update cannot launch {:?}", e))
                     } else {
@@ -363,8 +716,10 @@ pub fn make_event_loop(
     _policy: &Policy,
     _velocity: &Option<Velocity>,
     mut _ctrlr: Controller,
+    _flash: Arc<Mutex<FlashPersister>>,
     client_id: &str,
     _node_id: &PublicKey,
+    _decisions: Arc<Mutex<mpsc::Receiver<Decision>>>,
 ) -> Result<()> {
     log::info!("About to subscribe to the mpsc channel");
     while let Ok(event) = rx.recv() {
@@ -373,7 +728,7 @@ pub fn make_event_loop(
                 led_tx.send(Status::ConnectedToMqtt).unwrap();
                 mqtt_sub(&mut mqtt, client_id, &[topics::VLS]);
             }
-            Event::VlsMessage(msg_bytes) => {
+            Event::VlsMessage(msg_bytes, _) => {
                 led_tx.send(Status::Signing).unwrap();
                 let b = sphinx_signer::parse_ping_and_form_response(msg_bytes);
                 if do_log {
@@ -387,6 +742,8 @@ pub fn make_event_loop(
                 log::info!("GOT A Event::Disconnected msg!");
             }
             Event::Control(_) => (),
+            Event::ExtControl(_) => (),
+            Event::OtaChunk(_) => (),
         }
     }
 
@@ -0,0 +1,118 @@
+use crate::core::approval::Approvals;
+use crate::core::events::ROOT_STORE;
+use crate::core::{health, provision, reset, FlashPersister};
+use anyhow::{anyhow, Result};
+use glyph::control::ControlPersist;
+use glyph::sphinx_auther::nonce;
+use glyph::sphinx_auther::secp256k1::PublicKey;
+use sphinx_key_common::ext::{ExtControl, ExtResponse, OtaKeys, WifiNetwork};
+use sphinx_key_common::provision::SealedSeed;
+use sphinx_key_common::release::{rotation_message, KeySet};
+use sphinx_signer::sphinx_glyph as glyph;
+use std::sync::Mutex;
+
+// ExtControl msgs are checked against the same nonce as the glyph Controller.
+// The seed is the unlocked one in memory, with a PIN there is none in flash
+pub fn handle_ext_control(
+    pubkey: &PublicKey,
+    flash: &Mutex<FlashPersister>,
+    approvals: &Mutex<Approvals>,
+    seed: &[u8; 32],
+    msg_bytes: &[u8],
+) -> ExtResponse {
+    match parse_and_handle(pubkey, flash, approvals, seed, msg_bytes) {
+        Ok(res) => res,
+        Err(e) => {
+            log::warn!("error handling ext ctrl msg {:?}", e);
+            ExtResponse::Error(e.to_string())
+        }
+    }
+}
+
+fn parse_and_handle(
+    pubkey: &PublicKey,
+    flash: &Mutex<FlashPersister>,
+    approvals: &Mutex<Approvals>,
+    seed: &[u8; 32],
+    msg_bytes: &[u8],
+) -> Result<ExtResponse> {
+    let mut flash = flash.lock().unwrap();
+    let last_nonce = flash.read_nonce().unwrap_or(0);
+    let (data, new_nonce) = nonce::parse_msg(msg_bytes, pubkey, last_nonce)?;
+    flash.set_nonce(new_nonce)?;
+    let msg: ExtControl = serde_json::from_slice(&data)?;
+    log::info!("EXT CONTROL MSG {:?}", msg);
+    match msg {
+        ExtControl::QueryOtaKeys => Ok(ExtResponse::OtaKeys(ota_keys(&flash.read_ota_keys()))),
+        ExtControl::UpdateOtaKeys(update) => {
+            let addresses: Vec<&str> = update.addresses.iter().map(|a| a.as_str()).collect();
+            let next = KeySet::new(update.sequence, update.threshold, &addresses)
+                .map_err(|e| anyhow!("bad key set: {}", e))?;
+            let msg = rotation_message(update.sequence, update.threshold, &addresses)
+                .map_err(|e| anyhow!("{}", e))?;
+            flash
+                .read_ota_keys()
+                .verify_rotation(&next, msg.as_str(), &update.sigs)
+                .map_err(|e| anyhow!("key rotation refused: {}", e))?;
+            flash.write_ota_keys(&next)?;
+            log::info!("rotated to ota keys #{}", next.sequence());
+            Ok(ExtResponse::OtaKeys(ota_keys(&next)))
+        }
+        ExtControl::QueryWifi => Ok(wifi_networks(flash.read_wifi_networks())),
+        ExtControl::AddWifi(network) => Ok(wifi_networks(flash.add_wifi_network(network)?)),
+        ExtControl::RemoveWifi(ssid) => Ok(wifi_networks(flash.remove_wifi_network(&ssid)?)),
+        ExtControl::QueryStore => Ok(ExtResponse::Store(health::report(ROOT_STORE)?)),
+        ExtControl::Reset(mode) => {
+            reset::wipe(&mut flash, mode)?;
+            Ok(ExtResponse::Reset(mode))
+        }
+        ExtControl::QueryButton => Ok(ExtResponse::Button(flash.read_button_actions())),
+        ExtControl::SetButton(actions) => {
+            flash.write_button_actions(&actions)?;
+            Ok(ExtResponse::Button(actions))
+        }
+        ExtControl::QueryApprovals => Ok(ExtResponse::Approvals(approvals.lock().unwrap().list())),
+        ExtControl::Decide { id, approve } => {
+            let decided = approvals
+                .lock()
+                .unwrap()
+                .decide(&id, approve)
+                .ok_or(anyhow!("nothing waiting on approval as {}", id))?;
+            Ok(ExtResponse::Approvals(vec![decided]))
+        }
+        ExtControl::QueryBrightness => Ok(ExtResponse::Brightness(flash.read_led_brightness())),
+        ExtControl::SetBrightness(brightness) => {
+            flash.write_led_brightness(brightness)?;
+            Ok(ExtResponse::Brightness(brightness))
+        }
+        ExtControl::ExportSeed(pubkey) => {
+            log::warn!("exporting the seed to {}", pubkey);
+            let SealedSeed::Device { pubkey, cipher } = provision::seal(*seed, &pubkey)?;
+            Ok(ExtResponse::SeedBackup { pubkey, cipher })
+        }
+    }
+}
+
+fn ota_keys(keys: &KeySet) -> OtaKeys {
+    let mut buf = [0u8; 40];
+    let addresses = (0..keys.len())
+        .filter_map(|i| keys.address(i, &mut buf).map(|a| a.to_string()))
+        .collect();
+    OtaKeys {
+        sequence: keys.sequence(),
+        threshold: keys.threshold(),
+        addresses,
+        sigs: String::new(),
+    }
+}
+
+fn wifi_networks(networks: Vec<WifiNetwork>) -> ExtResponse {
+    let networks = networks
+        .into_iter()
+        .map(|n| WifiNetwork {
+            pass: String::new(),
+            ..n
+        })
+        .collect();
+    ExtResponse::WifiNetworks(networks)
+}
@@ -0,0 +1,184 @@
+use crate::core::store::EncryptedKVVStore;
+use anyhow::{anyhow, Result};
+use sphinx_key_common::ext::StoreHealth;
+use sphinx_signer::kvv::KVVStore;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+pub const QUARANTINE_DIR: &str = "/sdcard/quarantine";
+// compact's copy of the entry it is rewriting, outside the store so it
+// isn't counted as one of its files
+const REWRITE_PATH: &str = "/sdcard/rewrite.bin";
+// the oldest are dropped past this
+const MAX_QUARANTINED: usize = 64;
+
+// the file level pass, before the store is opened. A power cut while FAT
+// is writing a file leaves it empty, which the store can't read back
+pub fn sweep(root: &str) -> Result<usize> {
+    let mut swept = 0;
+    sweep_dir(Path::new(root), root, &mut swept)?;
+    trim_quarantine()?;
+    Ok(swept)
+}
+
+fn sweep_dir(dir: &Path, root: &str, swept: &mut usize) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            sweep_dir(&path, root, swept)?;
+        } else if fs::metadata(&path)?.len() == 0 {
+            let name = path.strip_prefix(root).unwrap_or(&path).to_string_lossy();
+            log::warn!("quarantining the empty file {}", name);
+            fs::rename(&path, next_path(&name, "empty")?)?;
+            *swept += 1;
+        }
+    }
+    Ok(())
+}
+
+// an entry the store can't open, or one LSS has a newer version of
+pub fn quarantine(key: &str, version: u64, raw: &[u8]) {
+    log::warn!("quarantining {} v{}", key, version);
+    let res =
+        next_path(key, &version.to_string()).and_then(|p| fs::write(p, raw).map_err(Into::into));
+    if let Err(e) = res {
+        log::error!("could not quarantine {}: {:?}", key, e);
+    }
+}
+
+// numbered, so the oldest sort first
+fn next_path(key: &str, ext: &str) -> Result<String> {
+    fs::create_dir_all(QUARANTINE_DIR)?;
+    let next = quarantined()?
+        .last()
+        .and_then(|n| n.split('-').next()?.parse::<u32>().ok())
+        .map_or(0, |n| n + 1);
+    let name = key.trim_start_matches('/').replace('/', "~");
+    Ok(format!("{}/{:06}-{}.{}", QUARANTINE_DIR, next, name, ext))
+}
+
+fn quarantined() -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    if let Ok(entries) = fs::read_dir(QUARANTINE_DIR) {
+        for entry in entries {
+            names.push(entry?.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+// what was replaced or set aside is only kept for a while
+fn trim_quarantine() -> Result<()> {
+    let names = quarantined()?;
+    let extra = names.len().saturating_sub(MAX_QUARANTINED);
+    for name in &names[..extra] {
+        fs::remove_file(format!("{}/{}", QUARANTINE_DIR, name))?;
+    }
+    Ok(())
+}
+
+// more files than entries under the store are old versions left behind,
+// so every entry is written back with only its latest. Each one is copied
+// out first, and a copy still there at the next boot is put back
+pub fn compact<S: KVVStore>(root: &str, store: &EncryptedKVVStore<S>) -> Result<usize> {
+    resume_rewrite(store)?;
+    let files = report(root)?.entries as usize;
+    let entries = store.len().map_err(|e| anyhow!("{:?}", e))?;
+    if files <= entries {
+        return Ok(0);
+    }
+    log::warn!("{} files for {} entries, compacting", files, entries);
+    let sealed_entries = store.sealed_entries().map_err(|e| anyhow!("{:?}", e))?;
+    for (key, (version, sealed)) in sealed_entries {
+        save_rewrite(&key, version, &sealed)?;
+        store
+            .rewrite(&key, version, sealed)
+            .map_err(|e| anyhow!("{:?}", e))?;
+        fs::remove_file(REWRITE_PATH)?;
+    }
+    let left = report(root)?.entries as usize;
+    if left > entries {
+        log::warn!("{} files under the store are no entry's", left - entries);
+    }
+    Ok(files - left)
+}
+
+// the version, the key length, the key and the sealed value
+fn save_rewrite(key: &str, version: u64, sealed: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(REWRITE_PATH)?;
+    file.write_all(&version.to_be_bytes())?;
+    file.write_all(&(key.len() as u16).to_be_bytes())?;
+    file.write_all(key.as_bytes())?;
+    file.write_all(sealed)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// a copy that was cut off itself is of an entry that wasn't touched yet
+fn resume_rewrite<S: KVVStore>(store: &EncryptedKVVStore<S>) -> Result<()> {
+    let bytes = match fs::read(REWRITE_PATH) {
+        Ok(b) => b,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    match parse_rewrite(&bytes) {
+        Some((key, version, sealed)) => {
+            let restored = store
+                .restore(key, version, sealed.to_vec())
+                .map_err(|e| anyhow!("{:?}", e))?;
+            if restored {
+                log::warn!("put back {} v{} from a cut off compact", key, version);
+            }
+        }
+        None => log::warn!("dropping a half written compact copy"),
+    }
+    fs::remove_file(REWRITE_PATH)?;
+    Ok(())
+}
+
+fn parse_rewrite(bytes: &[u8]) -> Option<(&str, u64, &[u8])> {
+    let version = u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?);
+    let key_len = u16::from_be_bytes(bytes.get(8..10)?.try_into().ok()?) as usize;
+    let key = std::str::from_utf8(bytes.get(10..10 + key_len)?).ok()?;
+    let sealed = bytes.get(10 + key_len..)?;
+    if sealed.is_empty() {
+        return None;
+    }
+    Some((key, version, sealed))
+}
+
+pub fn report(root: &str) -> Result<StoreHealth> {
+    let mut health = StoreHealth {
+        entries: 0,
+        bytes: 0,
+        quarantined: quarantined()?,
+    };
+    count(Path::new(root), &mut health)?;
+    Ok(health)
+}
+
+fn count(dir: &Path, health: &mut StoreHealth) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            count(&path, health)?;
+        } else {
+            health.entries += 1;
+            health.bytes += fs::metadata(&path)?.len();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use esp_idf_svc::sys;
+use sphinx_key_common::ext::{HeapPressure, HeapWarning};
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// of the largest free block, which is what the next big allocation needs
+const SOFT: u32 = 40_000;
+const HARD: u32 = 25_000;
+// back to normal this far over SOFT, so it doesn't flap
+const RECOVER: u32 = 8_000;
+const SAMPLE_EVERY: Duration = Duration::from_secs(1);
+
+static PRESSURE: AtomicU8 = AtomicU8::new(HeapPressure::Normal as u8);
+static FREE: AtomicU32 = AtomicU32::new(0);
+static LARGEST: AtomicU32 = AtomicU32::new(0);
+static LOWEST: AtomicU32 = AtomicU32::new(u32::MAX);
+
+// only samples, the event loop acts on it so it can finish a commit first
+pub fn watch() -> Result<()> {
+    let builder = thread::Builder::new().stack_size(3000);
+    builder.spawn(|| loop {
+        let (free, largest) = unsafe {
+            (
+                sys::heap_caps_get_free_size(sys::MALLOC_CAP_8BIT) as u32,
+                sys::heap_caps_get_largest_free_block(sys::MALLOC_CAP_8BIT) as u32,
+            )
+        };
+        FREE.store(free, Ordering::Relaxed);
+        LARGEST.store(largest, Ordering::Relaxed);
+        LOWEST.fetch_min(largest, Ordering::Relaxed);
+        let before = pressure();
+        let after = level(before, largest);
+        if after != before {
+            log::warn!("heap {:?}: {} free, largest block {}", after, free, largest);
+            PRESSURE.store(after as u8, Ordering::Relaxed);
+        }
+        thread::sleep(SAMPLE_EVERY);
+    })?;
+    Ok(())
+}
+
+pub fn pressure() -> HeapPressure {
+    match PRESSURE.load(Ordering::Relaxed) {
+        0 => HeapPressure::Normal,
+        1 => HeapPressure::Soft,
+        _ => HeapPressure::Hard,
+    }
+}
+
+pub fn warning() -> HeapWarning {
+    HeapWarning {
+        pressure: pressure(),
+        free: FREE.load(Ordering::Relaxed),
+        largest_block: LARGEST.load(Ordering::Relaxed),
+        lowest_block: LOWEST.load(Ordering::Relaxed),
+    }
+}
+
+// hard stays hard, it ends in a restart anyway
+fn level(current: HeapPressure, largest: u32) -> HeapPressure {
+    match current {
+        HeapPressure::Hard => HeapPressure::Hard,
+        _ if largest < HARD => HeapPressure::Hard,
+        _ if largest < SOFT => HeapPressure::Soft,
+        HeapPressure::Soft if largest < SOFT + RECOVER => HeapPressure::Soft,
+        _ => HeapPressure::Normal,
+    }
+}
@@ -1,11 +1,15 @@
 use crate::conn::mqtt::QOS;
 use crate::core::events::Event;
+use crate::core::store::EncryptedKVVStore;
+use crate::core::{health, restarts};
 use anyhow::{anyhow, Result};
 use esp_idf_svc::mqtt::client::ConnState;
 use esp_idf_svc::mqtt::client::EspMqttClient;
 use esp_idf_svc::mqtt::client::MessageImpl;
 use esp_idf_svc::sys::EspError;
 use lss_connector::{secp256k1::PublicKey, BrokerMutations, LssSigner, Msg as LssMsg};
+use sphinx_key_common::ext::RestartReason;
+use sphinx_signer::kvv::fs::FsKVVStore;
 use sphinx_signer::sphinx_glyph::topics;
 use sphinx_signer::{self, HandlerBuilder, RootHandler};
 use std::sync::mpsc;
@@ -18,8 +22,15 @@ pub fn init_lss(
     rx: &mpsc::Receiver<Event>,
     handler_builder: HandlerBuilder,
     mqtt: &mut EspMqttClient<ConnState<MessageImpl, EspError>>,
+    store: &EncryptedKVVStore<FsKVVStore>,
 ) -> Result<(RootHandler, LssSigner)> {
     let client_id = hex::encode(signer_id);
+    let blank = store
+        .is_empty()
+        .map_err(|e| anyhow!("failed to read the store {:?}", e))?;
+    if blank {
+        log::warn!("the store is blank, restoring it from LSS");
+    }
 
     let server_pubkey = loop {
         let event = rx.recv_timeout(Duration::from_secs(30))?;
@@ -42,13 +53,31 @@ pub fn init_lss(
         }
     };
 
-    let (mut init_handler, res2) = lss_signer.build_with_lss(created, handler_builder, None)?;
+    let cloud = created.muts.clone();
+    let (mut init_handler, res2) = lss_signer
+        .build_with_lss(created, handler_builder, None)
+        .map_err(|e| anyhow!("failed to build with LSS {:?}", e))?;
+    // only now that the server hmac checked out is anything from LSS
+    // written, whatever is missing here or older than in LSS. The handler
+    // was built from the old state, so it is loaded again after a restart
+    let applied = store
+        .apply_cloud(&cloud, health::quarantine)
+        .map_err(|e| anyhow!("failed to restore from LSS {:?}", e))?;
+    if applied > 0 {
+        let what = if blank { "restored" } else { "repaired" };
+        let context = format!("{} {} entries from LSS", what, applied);
+        restarts::restart(RestartReason::StoreRepaired, &context);
+    }
     let lss_res_2_topic = format!("{}/{}", client_id, topics::INIT_2_RES);
     mqtt.publish(&lss_res_2_topic, QOS, false, &res2)
         .expect("could not publish LSS response 2");
 
     let hsmd_init_bytes = loop {
         match rx.recv_timeout(Duration::from_secs(30))? {
+            // a QoS 1 redelivery of an earlier step is still an LSS msg
+            Event::LssMessage(hib) if LssMsg::from_slice(&hib).is_ok() => {
+                log::warn!("redelivered LSS init msg, skipping")
+            }
             Event::LssMessage(hib) => break hib,
             _ => log::warn!("not an LSS message"),
         }
@@ -1,5 +1,14 @@
+pub mod approval;
 pub mod config;
 pub mod control;
 pub mod events;
+pub mod ext;
+pub mod health;
+pub mod heap;
 pub mod lss;
+pub mod pin;
+pub mod provision;
+pub mod reset;
+pub mod restarts;
+pub mod store;
 pub use control::FlashPersister;
@@ -0,0 +1,159 @@
+use crate::core::FlashPersister;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::sys::{
+    esp, esp_efuse_block_t, esp_efuse_block_t_EFUSE_BLK_KEY0, esp_efuse_block_t_EFUSE_BLK_KEY5,
+    esp_efuse_block_t_EFUSE_BLK_KEY_MAX, esp_efuse_find_purpose, esp_efuse_key_block_unused,
+    esp_efuse_purpose_t_ESP_EFUSE_KEY_PURPOSE_HMAC_UP, esp_efuse_write_key, esp_fill_random,
+    esp_hmac_calculate, hmac_key_id_t,
+};
+use sphinx_crypter::chacha::{decrypt, encrypt, NONCE_LEN, PAYLOAD_LEN};
+use sphinx_crypter::secp256k1::rand::{thread_rng, RngCore};
+use sphinx_signer::sphinx_glyph::control::ControlPersist;
+use std::convert::TryInto;
+
+pub const MIN_PIN_LEN: usize = 4;
+pub const MAX_PIN_LEN: usize = 32;
+// wrong PINs in a row before the seed and config are wiped
+pub const MAX_ATTEMPTS: u64 = 10;
+const SALT_LEN: usize = 16;
+// the salt, then the chacha payload
+pub const SEALED_LEN: usize = SALT_LEN + PAYLOAD_LEN;
+// 128 * R * 2^LOG_N = 128KB for each guess, about what the heap can spare
+// next to the wifi driver
+const LOG_N: u8 = 10;
+const R: u32 = 1;
+const P: u32 = 1;
+// burned by burn_device_key if no eFuse key block holds an HMAC key yet
+const HMAC_KEY_BLOCK: esp_efuse_block_t = esp_efuse_block_t_EFUSE_BLK_KEY5;
+
+pub fn check_pin(pin: &str) -> Result<()> {
+    if !(MIN_PIN_LEN..=MAX_PIN_LEN).contains(&pin.len()) {
+        return Err(anyhow!(
+            "the PIN must be {} to {} characters",
+            MIN_PIN_LEN,
+            MAX_PIN_LEN
+        ));
+    }
+    Ok(())
+}
+
+// replaces the seed in the clear with one only the PIN opens
+pub fn lock_seed(flash: &mut FlashPersister, seed: [u8; 32], pin: &str) -> Result<()> {
+    check_pin(pin)?;
+    let mut salt = [0u8; SALT_LEN];
+    thread_rng().fill_bytes(&mut salt);
+    let mut nonce_end = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut nonce_end);
+    let cipher = encrypt(seed, pin_key(pin, &salt)?, nonce_end)?;
+    let mut sealed = [0u8; SEALED_LEN];
+    sealed[..SALT_LEN].copy_from_slice(&salt);
+    sealed[SALT_LEN..].copy_from_slice(&cipher);
+    flash.write_sealed_seed(&sealed)?;
+    flash.set_pin_failures(0)?;
+    if flash.read_seed().is_ok() {
+        flash.remove_seed()?;
+    }
+    Ok(())
+}
+
+pub fn is_locked(flash: &FlashPersister) -> bool {
+    flash.read_sealed_seed().is_some()
+}
+
+// the seed stays locked in flash, only the caller gets it in the clear
+pub fn unlock(flash: &mut FlashPersister, pin: &str) -> Result<[u8; 32]> {
+    let sealed = flash
+        .read_sealed_seed()
+        .ok_or(anyhow!("the seed is not PIN locked"))?;
+    // counted before trying, so cutting the power during a try doesn't
+    // give a free guess
+    let failures = flash.pin_failures() + 1;
+    flash.set_pin_failures(failures)?;
+    let cipher: [u8; PAYLOAD_LEN] = sealed[SALT_LEN..].try_into()?;
+    match decrypt(cipher, pin_key(pin, &sealed[..SALT_LEN])?) {
+        Ok(seed) => {
+            flash.set_pin_failures(0)?;
+            Ok(seed)
+        }
+        Err(_) if failures >= MAX_ATTEMPTS => {
+            log::error!("{} wrong PINs, wiping the seed", failures);
+            flash.remove_sealed_seed()?;
+            flash.remove_config()?;
+            flash.set_pin_failures(0)?;
+            Err(anyhow!("wrong PIN, the seed is wiped"))
+        }
+        Err(_) => Err(anyhow!("wrong PIN, {} tries left", MAX_ATTEMPTS - failures)),
+    }
+}
+
+// scrypt makes each guess cost memory as well as time, and the eFuse HMAC
+// ties the key to this chip, so a dump of the flash can't be guessed at
+// anywhere else
+fn pin_key(pin: &str, salt: &[u8]) -> Result<[u8; 32]> {
+    let params = scrypt::Params::new(LOG_N, R, P, 32).map_err(|e| anyhow!("{}", e))?;
+    let mut stretched = [0u8; 32];
+    scrypt::scrypt(pin.as_bytes(), salt, &params, &mut stretched).map_err(|e| anyhow!("{}", e))?;
+    device_hmac(&stretched)
+}
+
+// the HMAC peripheral reads its key straight from the eFuse, which is read
+// protected once burned, so no code on the chip can get at it either
+fn device_hmac(msg: &[u8; 32]) -> Result<[u8; 32]> {
+    let block = hmac_key_block()?;
+    let key_id = (block - esp_efuse_block_t_EFUSE_BLK_KEY0) as hmac_key_id_t;
+    let mut out = [0u8; 32];
+    esp!(unsafe {
+        esp_hmac_calculate(
+            key_id,
+            msg.as_ptr() as *const _,
+            msg.len(),
+            out.as_mut_ptr(),
+        )
+    })?;
+    Ok(out)
+}
+
+// the PIN needs the eFuse key, which is burned once and for good, by the
+// /config call that sets a PIN and by nothing else
+pub fn has_device_key() -> bool {
+    find_hmac_key().is_some()
+}
+
+pub fn burn_device_key() -> Result<()> {
+    if has_device_key() {
+        return Ok(());
+    }
+    if !unsafe { esp_efuse_key_block_unused(HMAC_KEY_BLOCK) } {
+        return Err(anyhow!("no eFuse key block left for the PIN key"));
+    }
+    log::warn!("burning the HMAC key for the PIN into eFuse");
+    let mut key = [0u8; 32];
+    unsafe { esp_fill_random(key.as_mut_ptr() as *mut _, key.len()) };
+    esp!(unsafe {
+        esp_efuse_write_key(
+            HMAC_KEY_BLOCK,
+            esp_efuse_purpose_t_ESP_EFUSE_KEY_PURPOSE_HMAC_UP,
+            key.as_ptr() as *const _,
+            key.len(),
+        )
+    })?;
+    Ok(())
+}
+
+fn hmac_key_block() -> Result<esp_efuse_block_t> {
+    find_hmac_key().ok_or(anyhow!(
+        "no PIN key in eFuse, set a PIN in the config first"
+    ))
+}
+
+fn find_hmac_key() -> Option<esp_efuse_block_t> {
+    let mut block = esp_efuse_block_t_EFUSE_BLK_KEY_MAX;
+    let found = unsafe {
+        esp_efuse_find_purpose(
+            esp_efuse_purpose_t_ESP_EFUSE_KEY_PURPOSE_HMAC_UP,
+            &mut block,
+        )
+    };
+    found.then_some(block)
+}
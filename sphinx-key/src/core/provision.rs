@@ -0,0 +1,119 @@
+use crate::core::config::ecdh_keypair;
+use crate::core::{pin, FlashPersister};
+use crate::random_16;
+use sphinx_crypter::secp256k1::rand::{thread_rng, RngCore};
+
+use anyhow::{anyhow, Result};
+use sphinx_crypter::chacha::{decrypt, encrypt, NONCE_LEN, PAYLOAD_LEN};
+use sphinx_crypter::ecdh::{derive_shared_secret_from_slice, PUBLIC_KEY_LEN};
+use sphinx_crypter::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use sphinx_key_common::provision::{Provision, SealedSeed};
+use sphinx_signer::sphinx_glyph::control::{Config, ControlPersist};
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::sync::Mutex;
+
+const PROVISION_PATH: &str = "/sdcard/provision.json";
+const DEVICE_PUB_PATH: &str = "/sdcard/device.pub";
+const NETWORKS: [&str; 5] = ["bitcoin", "mainnet", "testnet", "signet", "regtest"];
+
+// Ok(true) if a provisioning file was found and imported. The file is wiped
+// either way, it holds the wifi password and the seed
+pub fn import_from_sdcard(flash: &Mutex<FlashPersister>) -> Result<bool> {
+    let mut flash = flash.lock().unwrap();
+    let device_key = device_key(&mut flash)?;
+    let bytes = match fs::read(PROVISION_PATH) {
+        Ok(b) => b,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    wipe(PROVISION_PATH, bytes.len())?;
+    log::info!("found a provisioning file, importing it");
+
+    let prov: Provision = serde_json::from_slice(&bytes)?;
+    if !NETWORKS.contains(&prov.network.as_str()) {
+        return Err(anyhow!("unknown network {}", prov.network));
+    }
+    let seed = match &prov.seed {
+        Some(sealed) => Some(unseal(sealed, &device_key)?),
+        None => None,
+    };
+    let stored_seed = flash.read_seed().ok();
+    let locked = pin::is_locked(&flash);
+    match (seed, stored_seed) {
+        (Some(s), Some(stored)) if s != stored => {
+            return Err(anyhow!("a different seed is already stored"))
+        }
+        (Some(_), None) if locked => return Err(anyhow!("a PIN locked seed is already stored")),
+        (None, None) if !locked => return Err(anyhow!("no seed in the provisioning file")),
+        _ => (),
+    }
+
+    flash.write_config(Config {
+        broker: prov.broker,
+        ssid: prov.ssid,
+        pass: prov.pass,
+        network: prov.network,
+    })?;
+    if stored_seed.is_none() {
+        if let Some(s) = seed {
+            flash.write_seed(s)?;
+            flash.write_id(random_16())?;
+        }
+    }
+    Ok(true)
+}
+
+// made on first boot, and its pubkey left on the sdcard for the provisioner
+pub fn device_key(flash: &mut FlashPersister) -> Result<SecretKey> {
+    let sk = match flash.read_device_key() {
+        Some(k) => SecretKey::from_slice(&k)?,
+        None => {
+            let (sk, _) = ecdh_keypair();
+            flash.write_device_key(&sk.secret_bytes())?;
+            sk
+        }
+    };
+    if fs::metadata(DEVICE_PUB_PATH).is_err() {
+        let pk = PublicKey::from_secret_key(&Secp256k1::new(), &sk);
+        fs::write(DEVICE_PUB_PATH, hex::encode(pk.serialize()))?;
+    }
+    Ok(sk)
+}
+
+// the seed is only ever sealed to this device's key, the provisioner reads
+// its pubkey from device.pub. Nothing in the image can open the file
+fn unseal(sealed: &SealedSeed, device_key: &SecretKey) -> Result<[u8; 32]> {
+    let SealedSeed::Device { pubkey, cipher } = sealed;
+    let their_pk: [u8; PUBLIC_KEY_LEN] = hex::decode(pubkey)?[..].try_into()?;
+    let secret = derive_shared_secret_from_slice(their_pk, device_key.secret_bytes())?;
+    let cipher: [u8; PAYLOAD_LEN] = hex::decode(cipher)?[..].try_into()?;
+    Ok(decrypt(cipher, secret)?)
+}
+
+// the other way around from unsealing a SealedSeed::Device, for backups. A
+// one time key, whose pubkey goes along with the cipher
+pub fn seal(seed: [u8; 32], their_pubkey: &str) -> Result<SealedSeed> {
+    let their_pk: [u8; PUBLIC_KEY_LEN] = hex::decode(their_pubkey)?[..].try_into()?;
+    let (sk, pk) = ecdh_keypair();
+    let secret = derive_shared_secret_from_slice(their_pk, sk.secret_bytes())?;
+    let mut nonce_end = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut nonce_end);
+    let cipher = encrypt(seed, secret, nonce_end)?;
+    Ok(SealedSeed::Device {
+        pubkey: hex::encode(pk.serialize()),
+        cipher: hex::encode(cipher),
+    })
+}
+
+// overwritten in place before it is removed, so the plaintext is not left
+// in the freed clusters
+fn wipe(path: &str, len: usize) -> Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0u8; len])?;
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)?;
+    Ok(())
+}
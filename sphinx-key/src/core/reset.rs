@@ -0,0 +1,79 @@
+use crate::core::events::ROOT_STORE;
+use crate::core::health::QUARANTINE_DIR;
+use crate::core::restarts;
+use crate::core::FlashPersister;
+use crate::status::Status;
+use anyhow::Result;
+use glyph::control::ControlPersist;
+use sphinx_key_common::ext::{ResetMode, RestartReason};
+use sphinx_signer::sphinx_glyph as glyph;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// long enough to read the mode off the LED
+const CONFIRM: Duration = Duration::from_secs(3);
+
+// removing a missing NVS key is a no-op. The OTA keys, the firmware version,
+// the device key and the control nonce stay in every mode, so a reset can't
+// roll back the firmware or replay an old control msg
+pub fn wipe(flash: &mut FlashPersister, mode: ResetMode) -> Result<()> {
+    log::warn!("wiping {:?}", mode);
+    match mode {
+        ResetMode::Wifi => wipe_wifi(flash)?,
+        ResetMode::Policy => wipe_policy(flash)?,
+        ResetMode::Store => wipe_store()?,
+        ResetMode::Full => {
+            wipe_wifi(flash)?;
+            wipe_policy(flash)?;
+            wipe_store()?;
+            flash.remove_seed()?;
+            flash.remove_sealed_seed()?;
+            flash.remove_pin_failures()?;
+            flash.remove_id()?;
+        }
+    }
+    Ok(())
+}
+
+// shows what was wiped, then restarts
+pub fn confirm_and_restart(mode: ResetMode, led_tx: &mpsc::Sender<Status>) -> ! {
+    let _ = led_tx.send(Status::Wiped(mode));
+    thread::sleep(CONFIRM);
+    restarts::restart(RestartReason::Reset, &format!("{:?}", mode))
+}
+
+fn wipe_wifi(flash: &mut FlashPersister) -> Result<()> {
+    flash.remove_config()?;
+    flash.remove_wifi_networks()
+}
+
+fn wipe_policy(flash: &mut FlashPersister) -> Result<()> {
+    flash.remove_policy()?;
+    flash.remove_velocity()
+}
+
+// the store dir itself is kept, FsKVVStore expects it
+fn wipe_store() -> Result<()> {
+    remove_contents(Path::new(ROOT_STORE))?;
+    remove_contents(Path::new(QUARANTINE_DIR))
+}
+
+// file by file, remove_dir_all doesn't always manage on FAT
+fn remove_contents(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_contents(&path)?;
+            fs::remove_dir(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys;
+use sphinx_key_common::ext::{Restart, RestartReason};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+// the same namespace as the FlashPersister, with a handle of its own so it
+// works from anywhere, the panic hook too
+const NAMESPACE: &str = "sphinx";
+// the restarts not yet published, as json
+const RESTARTS_KEY: &str = "restarts";
+const BOOT_KEY: &str = "boots";
+// the oldest are dropped past this
+const MAX_RESTARTS: usize = 8;
+const MAX_CONTEXT: usize = 160;
+// a control char in the context is escaped to 6 bytes, and the rest of an
+// entry fits in 128
+const MAX_ENTRY_LEN: usize = 6 * MAX_CONTEXT + 128;
+const RESTARTS_BUF_LEN: usize = MAX_RESTARTS * MAX_ENTRY_LEN;
+// anything before this is a clock that wasn't synced
+const SYNCED_AFTER: u64 = 1_600_000_000;
+
+static NVS: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+static BOOT: AtomicU32 = AtomicU32::new(0);
+
+// counts the boot, records a crash the firmware couldn't, and hooks panics
+pub fn init(nvs: EspDefaultNvsPartition) {
+    let _ = NVS.set(nvs);
+    let res = open().and_then(|mut nvs| {
+        let mut buf = [0u8; 4];
+        let last = match nvs.get_raw(BOOT_KEY, &mut buf)? {
+            Some(&[a, b, c, d]) => u32::from_be_bytes([a, b, c, d]),
+            _ => 0,
+        };
+        nvs.set_raw(BOOT_KEY, &(last + 1).to_be_bytes())?;
+        BOOT.store(last + 1, Ordering::Relaxed);
+        let recorded = read(&nvs).is_ok_and(|r| r.iter().any(|r| r.boot == last));
+        if let Some(reason) = chip_reason().filter(|_| !recorded) {
+            push(
+                &mut nvs,
+                entry(reason, last, 0, "from the chip's reset reason"),
+            )?;
+        }
+        Ok(())
+    });
+    if let Err(e) = res {
+        log::error!("failed to count the boot {:?}", e);
+    }
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        record(RestartReason::Panic, &info.to_string());
+        default_hook(info);
+    }));
+}
+
+// records why before restarting, for the broker to see after the next connect
+pub fn restart(reason: RestartReason, context: &str) -> ! {
+    record(reason, context);
+    log::info!("restarting esp!");
+    unsafe { sys::esp_restart() };
+}
+
+pub fn record(reason: RestartReason, context: &str) {
+    log::warn!("restart {:?}: {}", reason, context);
+    let uptime = unsafe { sys::esp_timer_get_time() } as u64 / 1_000_000;
+    let boot = BOOT.load(Ordering::Relaxed);
+    let res = open().and_then(|mut nvs| push(&mut nvs, entry(reason, boot, uptime, context)));
+    if let Err(e) = res {
+        log::error!("failed to record the restart {:?}", e);
+    }
+}
+
+// the ones since the last connect, removed once read
+pub fn take() -> Vec<Restart> {
+    let Ok(mut nvs) = open() else {
+        return Vec::new();
+    };
+    // one that can't be read is dropped here, or no restart would be
+    // recorded after it
+    let restarts = read(&nvs).unwrap_or_else(|e| {
+        log::error!("dropping the restarts that can't be read {:?}", e);
+        Vec::new()
+    });
+    if let Err(e) = nvs.remove(RESTARTS_KEY) {
+        log::error!("failed to remove the restarts {:?}", e);
+    }
+    restarts
+}
+
+fn open() -> Result<EspDefaultNvs> {
+    let part = NVS.get().ok_or(anyhow!("no nvs yet"))?;
+    Ok(EspDefaultNvs::new(part.clone(), NAMESPACE, true)?)
+}
+
+// an entry that doesn't parse, from an older firmware say, is left out. An
+// error if the history is there but can't be read at all
+fn read(nvs: &EspDefaultNvs) -> Result<Vec<Restart>> {
+    let mut buf = vec![0u8; RESTARTS_BUF_LEN];
+    let Some(bytes) = nvs.get_raw(RESTARTS_KEY, &mut buf)? else {
+        return Ok(Vec::new());
+    };
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(bytes)?;
+    let total = entries.len();
+    let restarts: Vec<Restart> = entries
+        .into_iter()
+        .filter_map(|e| serde_json::from_value(e).ok())
+        .collect();
+    if restarts.len() < total {
+        log::warn!(
+            "left out {} restarts that can't be read",
+            total - restarts.len()
+        );
+    }
+    Ok(restarts)
+}
+
+// the history is kept as it is if it can't be read, for take() to drop
+fn push(nvs: &mut EspDefaultNvs, restart: Restart) -> Result<()> {
+    let mut restarts = read(nvs)?;
+    restarts.push(restart);
+    let extra = restarts.len().saturating_sub(MAX_RESTARTS);
+    restarts.drain(..extra);
+    nvs.set_raw(RESTARTS_KEY, &serde_json::to_vec(&restarts)?)?;
+    Ok(())
+}
+
+fn entry(reason: RestartReason, boot: u32, uptime: u64, context: &str) -> Restart {
+    let at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    Restart {
+        reason,
+        at: if at > SYNCED_AFTER { at } else { 0 },
+        uptime,
+        boot,
+        context: context.chars().take(MAX_CONTEXT).collect(),
+    }
+}
+
+// a restart the firmware asked for is recorded already, and there is no
+// core dump partition, so for the rest this is all there is
+#[allow(non_upper_case_globals)]
+fn chip_reason() -> Option<RestartReason> {
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_PANIC => Some(RestartReason::Panic),
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT
+        | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+        | sys::esp_reset_reason_t_ESP_RST_WDT => Some(RestartReason::Watchdog),
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => Some(RestartReason::Brownout),
+        _ => None,
+    }
+}
@@ -0,0 +1,250 @@
+use crate::bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use crate::bitcoin::hashes::{sha256, Hash, HashEngine};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::de::IgnoredAny;
+use sphinx_crypter::secp256k1::rand::{thread_rng, RngCore};
+use sphinx_signer::kvv::{KVVStore, KVV};
+use sphinx_signer::lightning_signer::persist::Error;
+use std::sync::Arc;
+
+// marks a value written by this store, anything else is from before it
+const MAGIC: &[u8; 4] = b"SKE1";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+// the key for the state on the sd card, so it goes with the seed
+pub fn store_key(seed: &[u8; 32]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(seed);
+    engine.input(b"sphinx-key/store");
+    Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+}
+
+// chacha20poly1305 over each value, under the inner store. The key and the
+// version are authenticated along with it, so a value can't be moved to
+// another key or passed off as a newer version. Handing back an older file
+// along with its own version, as when the whole card is rolled back, is
+// caught at boot by apply_cloud: LSS gets every mutation before the signer
+// replies, so it never has an older version than the card. The keys
+// themselves stay in the clear, get_prefix needs them. Clones share the
+// inner store
+pub struct EncryptedKVVStore<S: KVVStore> {
+    inner: Arc<S>,
+    cipher: ChaCha20Poly1305,
+}
+
+// the state LSS hands over at init
+pub type Entry = (String, (u64, Vec<u8>));
+
+impl<S: KVVStore> EncryptedKVVStore<S> {
+    pub fn new(inner: S, key: [u8; 32]) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    // nothing on the card, as with a new or replaced one
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.inner.get_prefix("")?.next().is_none())
+    }
+
+    pub fn len(&self) -> Result<usize, Error> {
+        Ok(self.inner.get_prefix("")?.count())
+    }
+
+    // every entry is opened and decoded, and the ones that don't are handed
+    // to `quarantine` with their raw bytes and removed
+    pub fn check(&self, mut quarantine: impl FnMut(&str, u64, &[u8])) -> Result<usize, Error> {
+        let mut bad = 0;
+        for KVV(key, (version, sealed)) in self.inner.get_prefix("")? {
+            let opened = self.open(&key, version, &sealed);
+            if let Err(e) = opened.and_then(|value| decode(&key, &value)) {
+                log::warn!("{:?}", e);
+                quarantine(&key, version, &sealed);
+                self.inner.delete(&key)?;
+                bad += 1;
+            }
+        }
+        Ok(bad)
+    }
+
+    // writes what LSS has a newer version of, or what is missing here. The
+    // older entries go to `quarantine` first, they are a rolled back card or
+    // a write lost to a power cut
+    pub fn apply_cloud(
+        &self,
+        cloud: &[Entry],
+        mut quarantine: impl FnMut(&str, u64, &[u8]),
+    ) -> Result<usize, Error> {
+        let mut applied = 0;
+        for (key, (version, value)) in cloud {
+            match self.inner.get(key)? {
+                Some((local, _)) if local >= *version => continue,
+                Some((local, sealed)) => {
+                    log::warn!("{} rolled back to v{}, LSS has v{}", key, local, version);
+                    quarantine(key, local, &sealed)
+                }
+                None => (),
+            }
+            self.put_with_version(key, *version, value.clone())?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    // every entry as it is on the card, still sealed
+    pub fn sealed_entries(&self) -> Result<Vec<Entry>, Error> {
+        Ok(self
+            .inner
+            .get_prefix("")?
+            .map(|KVV(key, value)| (key, value))
+            .collect())
+    }
+
+    // deletes the entry, with whatever versions of it the inner store kept,
+    // and writes it back as it was. It is gone in between, so the caller
+    // keeps a copy until this returns, see health::compact
+    pub fn rewrite(&self, key: &str, version: u64, sealed: Vec<u8>) -> Result<(), Error> {
+        self.inner.delete(key)?;
+        self.inner.put_with_version(key, version, sealed)?;
+        match self.inner.get_version(key)? {
+            Some(v) if v == version => Ok(()),
+            _ => Err(Error::Internal(format!("{} was not written back", key))),
+        }
+    }
+
+    // puts back an entry a rewrite was cut off in, unless it made it
+    pub fn restore(&self, key: &str, version: u64, sealed: Vec<u8>) -> Result<bool, Error> {
+        if self.inner.get_version(key)?.is_some_and(|v| v >= version) {
+            return Ok(false);
+        }
+        self.inner.put_with_version(key, version, sealed)?;
+        Ok(true)
+    }
+
+    // encrypts the values written before this store, keeping their versions.
+    // Each one is a single overwrite, so a power cut only leaves some of them
+    // to do on the next boot. Only until it first finishes, see the
+    // "storemig" flag in core/control.rs
+    pub fn migrate(&self) -> Result<usize, Error> {
+        let mut migrated = 0;
+        for KVV(key, (version, value)) in self.inner.get_prefix("")? {
+            if value.starts_with(MAGIC) {
+                continue;
+            }
+            let sealed = self.seal(&key, version, &value)?;
+            self.inner.put_with_version(&key, version, sealed)?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+
+    fn seal(&self, key: &str, version: u64, value: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let aad = aad(key, version);
+        let ct = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: value,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::Internal(format!("could not encrypt {}", key)))?;
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ct.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ct);
+        Ok(sealed)
+    }
+
+    fn open(&self, key: &str, version: u64, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        let body = sealed
+            .strip_prefix(MAGIC)
+            .filter(|b| b.len() >= NONCE_LEN + TAG_LEN)
+            .ok_or_else(|| Error::Internal(format!("{} is not encrypted", key)))?;
+        let (nonce, ct) = body.split_at(NONCE_LEN);
+        let aad = aad(key, version);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ct, aad: &aad })
+            .map_err(|_| Error::Internal(format!("{} v{} was tampered with", key, version)))
+    }
+}
+
+// by hand, derive would want S: Clone
+impl<S: KVVStore> Clone for EncryptedKVVStore<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            cipher: self.cipher.clone(),
+        }
+    }
+}
+
+// the persister writes every value as msgpack, this only checks it parses
+fn decode(key: &str, value: &[u8]) -> Result<(), Error> {
+    rmp_serde::from_slice::<IgnoredAny>(value)
+        .map(|_| ())
+        .map_err(|e| Error::Internal(format!("{} does not decode: {}", key, e)))
+}
+
+fn aad(key: &str, version: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(key.len() + 8);
+    aad.extend_from_slice(key.as_bytes());
+    aad.extend_from_slice(&version.to_be_bytes());
+    aad
+}
+
+impl<S: KVVStore> KVVStore for EncryptedKVVStore<S> {
+    type Iter = std::vec::IntoIter<KVV>;
+
+    fn put(&self, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        // the version has to be known before encrypting
+        let version = self.inner.get_version(key)?.map_or(0, |v| v + 1);
+        self.put_with_version(key, version, value)
+    }
+    fn put_with_version(&self, key: &str, version: u64, value: Vec<u8>) -> Result<(), Error> {
+        let sealed = self.seal(key, version, &value)?;
+        self.inner.put_with_version(key, version, sealed)
+    }
+    fn put_batch(&self, kvvs: Vec<KVV>) -> Result<(), Error> {
+        let sealed = kvvs
+            .into_iter()
+            .map(|KVV(key, (version, value))| {
+                let sealed = self.seal(&key, version, &value)?;
+                Ok(KVV(key, (version, sealed)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.inner.put_batch(sealed)
+    }
+    fn get(&self, key: &str) -> Result<Option<(u64, Vec<u8>)>, Error> {
+        match self.inner.get(key)? {
+            Some((version, sealed)) => Ok(Some((version, self.open(key, version, &sealed)?))),
+            None => Ok(None),
+        }
+    }
+    fn get_version(&self, key: &str) -> Result<Option<u64>, Error> {
+        self.inner.get_version(key)
+    }
+    fn get_prefix(&self, prefix: &str) -> Result<Self::Iter, Error> {
+        let kvvs = self
+            .inner
+            .get_prefix(prefix)?
+            .map(|KVV(key, (version, sealed))| {
+                let value = self.open(&key, version, &sealed)?;
+                Ok(KVV(key, (version, value)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(kvvs.into_iter())
+    }
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        self.inner.delete(key)
+    }
+    fn clear_database(&self) -> Result<(), Error> {
+        self.inner.clear_database()
+    }
+}
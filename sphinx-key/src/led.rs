@@ -1,41 +1,48 @@
+use crate::core::restarts;
 use crate::status::Status;
 use anyhow::Result;
 use esp_idf_svc::hal::rmt::config::TransmitConfig;
 use esp_idf_svc::hal::rmt::{FixedLengthSignal, PinState, Pulse, TxRmtDriver};
 use esp_idf_svc::hal::{gpio, rmt};
-use std::collections::BTreeMap;
-use std::sync::{mpsc, Arc, Mutex};
+use esp_idf_svc::sys::EspError;
+use sphinx_key_common::ext::{ResetMode, RestartReason};
+use sphinx_key_common::led::{ErrorCode, Pattern, Player, Priority, Rgb, DEFAULT_BRIGHTNESS};
+use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 
-type Color = u32;
-type Time = u32;
+// for Status::ShowStatus
+const SHOW_BLINK: u16 = 1000;
+const SHOW_BLINKS: u8 = 3;
 
-pub struct Led {
-    brg: Color,
-    blink_length: Time,
-}
-
-fn states() -> BTreeMap<Status, (Color, Time)> {
-    let mut s = BTreeMap::new();
-    s.insert(Status::MountingSDCard, (0x000102, 100)); // Cyan
-    s.insert(Status::SyncingTime, (0x000122, 100)); // Cyan
-    s.insert(Status::WifiAccessPoint, (0x000100, 100)); // Green
-    s.insert(Status::Configuring, (0x010000, 20)); // Red
-    s.insert(Status::ConnectingToWifi, (0x010100, 350)); // Yellow
-    s.insert(Status::ConnectingToMqtt, (0x010001, 100)); // Purple
-    s.insert(Status::Connected, (0x000101, 400)); // Cyan
-    s.insert(Status::Signing, (0x111111, 100)); // White
-    s.insert(Status::Ota, (0xffa500, 100)); // Orange
-    s.insert(Status::Waiting, (0x000001, 100)); // Blue
-    s.insert(Status::Starting, (0x000001, 100)); // Blue
-    s.insert(Status::Reset1a, (0x017700, 100)); // yellow
-    s.insert(Status::Reset1, (0x017700, 100)); // yellow
-    s.insert(Status::Reset2a, (0xffa500, 100)); // orange
-    s.insert(Status::Reset2, (0xffa500, 100)); // orange
-    s.insert(Status::Reset3a, (0x010000, 100)); // Red
-    s.insert(Status::Reset3, (0x010000, 100)); // Red
-    s
+// the patterns are in common/src/led.rs
+fn pattern(status: Status) -> Option<Pattern> {
+    let s = match status {
+        Status::MountingSDCard => "cyan:100 off:400",
+        Status::SyncingTime => "blue:100 cyan:100 off:400",
+        Status::WifiAccessPoint => "green:100 off:400",
+        Status::Configuring => "red:20 off:400",
+        Status::ConnectingToWifi => "yellow:350 off:400",
+        Status::ConnectingToMqtt => "purple:100 off:400",
+        Status::Connected => "cyan:400 off:400",
+        Status::Signing => "white:100 off:400",
+        Status::Approving => "magenta:20 off:400",
+        Status::Ota => "orange:100 off:400",
+        Status::Waiting | Status::Starting => "blue:100 off:400",
+        Status::Reset1a | Status::Reset1 => "yellow:100 off:400",
+        Status::Reset2a | Status::Reset2 => "orange:100 off:400",
+        Status::Reset3a | Status::Reset3 => "red:100 off:400",
+        Status::Reset(ResetMode::Wifi) => "yellow:100 off:400",
+        Status::Reset(ResetMode::Policy) => "purple:100 off:400",
+        Status::Reset(ResetMode::Store) => "cyan:100 off:400",
+        Status::Reset(ResetMode::Full) => "red:20 off:400",
+        Status::Wiped(ResetMode::Wifi) => "yellow:400 off:400",
+        Status::Wiped(ResetMode::Policy) => "purple:400 off:400",
+        Status::Wiped(ResetMode::Store) => "cyan:400 off:400",
+        Status::Wiped(ResetMode::Full) => "red:400 off:400",
+        Status::Error(_) | Status::ShowStatus | Status::Brightness(_) => return None,
+    };
+    Some(Pattern::must(s, Priority::Status))
 }
 
 pub fn led_control_loop(
@@ -44,74 +51,81 @@ pub fn led_control_loop(
     rx: mpsc::Receiver<Status>,
 ) -> Result<()> {
     let config = TransmitConfig::new().clock_divider(1);
-    let transmit = Arc::new(Mutex::new(
-        TxRmtDriver::new(channel0, gpio0, &config).unwrap(),
-    ));
+    let mut tx = TxRmtDriver::new(channel0, gpio0, &config)?;
     let builder = thread::Builder::new().stack_size(2500);
     builder.spawn(move || {
-        let mut led = Led::new(0x000001, 100);
-        let states = states();
+        let mut player = Player::new(DEFAULT_BRIGHTNESS);
+        let mut current = Status::Starting;
+        player.play(pattern(current).unwrap());
         loop {
-            if let Ok(status) = rx.try_recv() {
+            while let Ok(status) = rx.try_recv() {
                 log::info!("LED STATUS: {:?}", status);
-                if let Some(s) = states.get(&status) {
-                    led.set(s.0, s.1);
+                match status {
+                    Status::Brightness(b) => player.set_brightness(b),
+                    Status::Error(code) => player.play(Pattern::error(code)),
+                    Status::ShowStatus => {
+                        if let Some(p) = pattern(current) {
+                            let color = p.steps()[0].color;
+                            let show = Pattern::blink(
+                                color,
+                                SHOW_BLINK,
+                                400,
+                                SHOW_BLINKS,
+                                Priority::Notice,
+                            );
+                            player.play(show);
+                        }
+                    }
+                    status => {
+                        if let Some(p) = pattern(status) {
+                            current = status;
+                            player.clear(Priority::Notice);
+                            player.play(p);
+                        }
+                    }
                 }
             }
-            led.blink(transmit.clone());
-            thread::sleep(Duration::from_millis(400));
+            let step = player.step();
+            if let Err(e) = neopixel(step.color, &mut tx) {
+                log::error!("failed to set the LED {:?}", e);
+            }
+            thread::sleep(Duration::from_millis(step.millis.into()));
         }
     })?;
     Ok(())
 }
 
-impl Led {
-    pub fn new(rgb: Color, blink_length: Time) -> Led {
-        Led {
-            brg: rotate_rgb(rgb),
-            blink_length,
-        }
-    }
-
-    pub fn set(&mut self, rgb: Color, blink_length: Time) {
-        self.brg = rotate_rgb(rgb);
-        self.blink_length = blink_length;
-    }
-
-    pub fn blink(&mut self, transmit: Arc<Mutex<TxRmtDriver>>) {
-        // Prepare signal
-        let mut tx = transmit.lock().unwrap();
-        let ticks_hz = tx.counter_clock().unwrap();
-        let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(350)).unwrap();
-        let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(800)).unwrap();
-        let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(700)).unwrap();
-        let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(600)).unwrap();
-        // Set led color
-        let mut signal = FixedLengthSignal::<24>::new();
-        for i in 0..24 {
-            let bit = 2_u32.pow(i) & self.brg != 0;
-            let (high_pulse, low_pulse) = if bit { (t1h, t1l) } else { (t0h, t0l) };
-            signal.set(i as usize, &(high_pulse, low_pulse)).unwrap();
-        }
-        // Set high and wait
-        tx.start_blocking(&signal).unwrap();
-        // FreeRtos::delay_ms(self.blink_length);
-        thread::sleep(Duration::from_millis(self.blink_length.into()));
-        // Set low
-        let mut signal = FixedLengthSignal::<24>::new();
-        for i in 0..24 {
-            signal.set(i as usize, &(t0h, t0l)).unwrap();
-        }
-        tx.start_blocking(&signal).unwrap();
-    }
+// shows the blink code once through before restarting, so a signer stuck
+// restarting on the same error can be told apart
+pub fn restart_with_error(
+    code: ErrorCode,
+    led_tx: &mpsc::Sender<Status>,
+    reason: RestartReason,
+    context: &str,
+) -> ! {
+    let _ = led_tx.send(Status::Error(code));
+    thread::sleep(Duration::from_millis(Pattern::error(code).millis().into()));
+    restarts::restart(reason, context)
 }
 
 fn ns(nanos: u64) -> Duration {
     Duration::from_nanos(nanos)
 }
 
-fn rotate_rgb(rgb: u32) -> u32 {
-    let b_mask: u32 = 0xff;
-    let blue = (rgb & b_mask) << 16;
-    blue | (rgb >> 8)
+// the same as the factory app, most significant bit first
+fn neopixel(rgb: Rgb, tx: &mut TxRmtDriver) -> Result<(), EspError> {
+    let color = rgb.grb();
+    let ticks_hz = tx.counter_clock()?;
+    let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(350))?;
+    let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(800))?;
+    let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(700))?;
+    let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(600))?;
+    let mut signal = FixedLengthSignal::<24>::new();
+    for i in (0..24).rev() {
+        let bit = 2_u32.pow(i) & color != 0;
+        let (high_pulse, low_pulse) = if bit { (t1h, t1l) } else { (t0h, t0l) };
+        signal.set(23 - i as usize, &(high_pulse, low_pulse))?;
+    }
+    tx.start_blocking(&signal)?;
+    Ok(())
 }
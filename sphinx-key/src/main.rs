@@ -10,10 +10,11 @@ mod status;
 pub(crate) use sphinx_signer::lightning_signer::bitcoin;
 
 use crate::bitcoin::Network;
-use crate::button::button_loop;
+use crate::button::{button_loop, Decision};
+use crate::conn::wifi::saved_networks;
 use crate::core::control::controller_from_seed;
-use crate::core::{config::*, events::*, FlashPersister};
-use crate::led::led_control_loop;
+use crate::core::{config::*, events::*, heap, pin, provision, restarts, FlashPersister};
+use crate::led::{led_control_loop, restart_with_error};
 #[allow(unused_imports)]
 use crate::sd::{mount_sd_card, simple_fs_test};
 use crate::status::Status;
@@ -24,6 +25,8 @@ use esp_idf_svc::hal::peripherals::Peripherals;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 #[allow(unused_imports)]
 use esp_idf_svc::sys as _;
+use sphinx_key_common::ext::RestartReason;
+use sphinx_key_common::led::ErrorCode;
 use sphinx_signer::sphinx_glyph::control::{Config, ControlPersist, Policy, Velocity};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
@@ -31,6 +34,9 @@ use std::time::Duration;
 use std::time::SystemTime;
 
 const ID_LEN: usize = 16;
+// in the access point after none of the saved wifi networks came up,
+// before restarting to try them again
+const AP_FALLBACK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
 fn main() -> Result<()> {
     // Temporary. Will disappear once ESP-IDF 4.4 is released, but for now it is necessary to call this function once,
@@ -56,23 +62,44 @@ fn main() -> Result<()> {
     println!("About to mount the sdcard...");
     while let Err(_e) = mount_sd_card() {
         println!("Failed to mount sd card. Make sure it is connected, trying again...");
+        led_tx.send(Status::Error(ErrorCode::SdCard)).unwrap();
         thread::sleep(Duration::from_secs(5));
     }
     println!("SD card mounted!");
 
     // let default_nav_partition = EspDefaultNvs.take().unwrap();
     let default_nvs = EspDefaultNvsPartition::take()?;
+    restarts::init(default_nvs.clone());
+    if let Err(e) = heap::watch() {
+        log::error!("unable to spawn the heap watchdog: {:?}", e);
+    }
     // let default_nvs = Arc::new();
     let flash_per = FlashPersister::new(default_nvs.clone());
+    led_tx
+        .send(Status::Brightness(flash_per.read_led_brightness()))
+        .unwrap();
     let flash_arc = Arc::new(Mutex::new(flash_per));
+    // approvals from the button, taken by the signing loop
+    let (decision_tx, decision_rx) = mpsc::channel::<Decision>();
+    let decision_rx = Arc::new(Mutex::new(decision_rx));
     // BUTTON thread
-    while let Err(e) = button_loop(unsafe { Gpio9::new() }, led_tx.clone(), flash_arc.clone()) {
+    while let Err(e) = button_loop(
+        unsafe { Gpio9::new() },
+        led_tx.clone(),
+        flash_arc.clone(),
+        decision_tx.clone(),
+    ) {
         log::error!("unable to spawn button thread: {:?}", e);
         thread::sleep(Duration::from_millis(1000));
     }
+    match provision::import_from_sdcard(&flash_arc) {
+        Ok(true) => println!("Imported the provisioning file from the sd card"),
+        Ok(false) => (),
+        Err(e) => log::error!("Could not import the provisioning file: {}", e),
+    }
     let flash = flash_arc.lock().unwrap();
     if let Ok(exist) = flash.read_config() {
-        let seed = flash.read_seed().expect("no seed...");
+        let seed = flash.read_seed().ok();
         let id = flash.read_id().expect("no id...");
         let policy = flash.read_policy().unwrap_or_default();
         let velocity = flash.read_velocity().ok();
@@ -81,21 +108,49 @@ fn main() -> Result<()> {
             "=============> START CLIENT NOW <============== {:?}",
             exist
         );
+        let seed = match seed {
+            Some(s) => s,
+            None => {
+                led_tx.send(Status::WifiAccessPoint).unwrap();
+                let modem = unsafe { peripherals.modem.clone_unchecked() };
+                match wait_for_pin(modem, default_nvs.clone(), flash_arc.clone()) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log::error!("Could not unlock the seed: {}", e);
+                        restarts::restart(RestartReason::Pin, &e.to_string());
+                    }
+                }
+            }
+        };
         led_tx.send(Status::ConnectingToWifi).unwrap();
-        let _wifi = match start_wifi_client(peripherals.modem, default_nvs, &exist) {
+        let networks = saved_networks(&exist, flash_arc.lock().unwrap().read_wifi_networks());
+        let modem = unsafe { peripherals.modem.clone_unchecked() };
+        let _wifi = match start_wifi_client(modem, default_nvs.clone(), &networks) {
             Ok(wifi) => wifi,
             Err(e) => {
                 log::error!("Could not setup wifi: {}", e);
-                log::info!("Restarting esp!");
-                unsafe { esp_idf_svc::sys::esp_restart() };
+                log::info!("Falling back to the access point to reconfigure wifi");
+                led_tx.send(Status::Error(ErrorCode::Wifi)).unwrap();
+                led_tx.send(Status::WifiAccessPoint).unwrap();
+                let timeout = Some(AP_FALLBACK_TIMEOUT);
+                if let Err(e) =
+                    run_config_server(peripherals.modem, default_nvs, &flash_arc, timeout)
+                {
+                    log::error!("{}", e);
+                }
+                restarts::restart(RestartReason::Wifi, "none of the saved networks came up");
             }
         };
 
         led_tx.send(Status::SyncingTime).unwrap();
         if let Err(e) = conn::sntp::sync_time_timeout() {
             log::error!("Could not setup sntp: {}", e);
-            log::info!("Restarting esp!");
-            unsafe { esp_idf_svc::sys::esp_restart() };
+            restart_with_error(
+                ErrorCode::Wifi,
+                &led_tx,
+                RestartReason::Sntp,
+                &e.to_string(),
+            );
         }
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -116,44 +171,65 @@ fn main() -> Result<()> {
                 &velocity,
                 led_tx.clone(),
                 flash_arc.clone(),
+                decision_rx.clone(),
             ) {
                 println!("Exited out of the event loop, trying again in 5 seconds...");
                 thread::sleep(Duration::from_secs(5));
             } else {
                 println!("Failed to setup MQTT. Make sure the details are correct, trying again in 5 seconds...");
+                led_tx.send(Status::Error(ErrorCode::Broker)).unwrap();
                 thread::sleep(Duration::from_secs(5));
             }
         }
     } else {
         led_tx.send(Status::WifiAccessPoint).unwrap();
         println!("=============> START SERVER NOW AND WAIT <==============");
-        let stored_seed = flash.read_seed().ok();
         drop(flash);
-        match start_config_server_and_wait(peripherals.modem, default_nvs, stored_seed.is_some()) {
-            Ok((_wifi, config, seed_opt)) => {
-                let mut flash = flash_arc.lock().unwrap();
-                flash.write_config(config).expect("could not store config");
-                if stored_seed.is_none() {
-                    match seed_opt {
-                        Some(s) => flash.write_seed(s).expect("could not store seed"),
-                        None => panic!("SEED REQUIRED!!!"),
-                    }
-                    flash.write_id(random_16()).expect("could not store id");
-                }
-                drop(flash);
-                println!("CONFIG SAVED");
-                thread::sleep(Duration::from_secs(2));
-                unsafe { esp_idf_svc::sys::esp_restart() };
-            }
-            Err(msg) => {
-                log::error!("{}", msg);
-            }
+        if let Err(msg) = run_config_server(peripherals.modem, default_nvs, &flash_arc, None) {
+            log::error!("{}", msg);
         }
     }
 
     Ok(())
 }
 
+// saves the config from the phone and restarts
+fn run_config_server(
+    modem: impl Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
+    default_nvs: EspDefaultNvsPartition,
+    flash_arc: &Arc<Mutex<FlashPersister>>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let has_stored_seed = flash_arc.lock().unwrap().has_seed();
+    let (_wifi, (config, seed_opt, pin_opt)) = start_config_server_and_wait(
+        modem,
+        default_nvs,
+        flash_arc.clone(),
+        has_stored_seed,
+        timeout,
+    )?;
+    let mut flash = flash_arc.lock().unwrap();
+    flash.write_config(config).expect("could not store config");
+    if !has_stored_seed {
+        match seed_opt {
+            Some(s) => flash.write_seed(s).expect("could not store seed"),
+            None => panic!("SEED REQUIRED!!!"),
+        }
+        flash.write_id(random_16()).expect("could not store id");
+    }
+    // a seed that is already PIN locked keeps its PIN
+    if let Some(pin) = pin_opt {
+        match flash.read_seed() {
+            Ok(s) => pin::lock_seed(&mut flash, s, &pin).expect("could not lock the seed"),
+            Err(_) => log::warn!("the seed is already PIN locked"),
+        }
+    }
+    drop(flash);
+    println!("CONFIG SAVED");
+    thread::sleep(Duration::from_secs(2));
+    restarts::restart(RestartReason::Config, "saved from the config server");
+}
+
 fn make_and_launch_client(
     config: Config,
     seed: [u8; 32],
@@ -162,20 +238,14 @@ fn make_and_launch_client(
     velocity: &Option<Velocity>,
     led_tx: mpsc::Sender<Status>,
     flash: Arc<Mutex<FlashPersister>>,
+    decisions: Arc<Mutex<mpsc::Receiver<Decision>>>,
 ) -> anyhow::Result<()> {
     let (tx, rx) = mpsc::channel();
 
-    let network = match config.network.as_str() {
-        "bitcoin" => Network::Bitcoin,
-        "mainnet" => Network::Bitcoin,
-        "testnet" => Network::Testnet,
-        "signet" => Network::Signet,
-        "regtest" => Network::Regtest,
-        _ => Network::Regtest,
-    };
+    let network = parse_network(&config.network).unwrap_or(Network::Regtest);
 
     // make the controller to validate Control messages
-    let ctrlr = controller_from_seed(&network, &seed[..], flash);
+    let ctrlr = controller_from_seed(&network, &seed[..], flash.clone());
 
     let pubkey = ctrlr.pubkey();
     let pubkey_str = hex::encode(pubkey.serialize());
@@ -202,8 +272,10 @@ fn make_and_launch_client(
         policy,
         velocity,
         ctrlr,
+        flash,
         &signer_id,
         &pubkey,
+        decisions,
     );
     Ok(())
 }
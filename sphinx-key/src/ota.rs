@@ -4,14 +4,43 @@ use esp_idf_svc::http::client::EspHttpConnection;
 use esp_idf_svc::http::client::FollowRedirectsPolicy::FollowNone;
 use esp_idf_svc::http::Method;
 use esp_idf_svc::ota::EspOta;
-use log::{error, info};
+use esp_idf_svc::sys::{
+    esp, esp_ota_get_running_partition, esp_ota_get_state_partition, esp_ota_img_states_t,
+    esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY,
+};
+use log::{error, info, warn};
+use sphinx_key_common::ota::{self as ota_proto, Manifest, Progress, Stage};
+use sphinx_key_common::release::{verify_release, KeySet};
 use sphinx_signer::sphinx_glyph::control::OtaParams;
-use std::fs::{remove_file, File};
-use std::io::BufWriter;
+use std::fs::{self, remove_file, File, OpenOptions};
 use std::io::Write;
+use std::io::{BufReader, BufWriter};
+use std::thread;
+use std::time::Duration;
+
+use crate::bitcoin::hashes::{sha256, HashEngine};
 
 const BUFFER_LEN: usize = 1024;
 const UPDATE_BIN_PATH: &str = "/sdcard/update.bin";
+const UPDATE_MAN_PATH: &str = "/sdcard/update.man";
+// the sha256_hash of the image in update.bin, so a partial file is only resumed for the same image
+const UPDATE_HASH_PATH: &str = "/sdcard/update.sha";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const DOWNLOAD_ATTEMPTS: u8 = 5;
+// publish progress every this many bytes
+const PROGRESS_EVERY: u32 = 64 * 1024;
+
+// until this is called, every restart of a freshly flashed image counts as a
+// failed boot, and the factory app reverts to the previous image after a few.
+// True if this is the first time for the running image
+pub fn mark_running_slot_valid() -> Result<bool> {
+    let mut state: esp_ota_img_states_t = 0;
+    let err = unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) };
+    // no otadata entry when it was flashed over the wire
+    let fresh = esp!(err).is_ok() && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY;
+    EspOta::new()?.mark_running_slot_valid()?;
+    Ok(fresh)
+}
 
 fn factory_reset() -> Result<()> {
     let mut ota = EspOta::new()?;
@@ -26,63 +55,216 @@ fn factory_reset() -> Result<()> {
     }
 }
 
-fn get_update(params: &OtaParams) -> Result<()> {
+// the bytes of the image on the sdcard so far, hashed as they are written
+struct Download {
+    engine: sha256::HashEngine,
+    written: u32,
+    total: Option<u32>,
+}
+
+impl Download {
+    fn new() -> Self {
+        Self {
+            engine: sha256::HashEngine::default(),
+            written: 0,
+            total: None,
+        }
+    }
+    // start over with an empty update.bin
+    fn fresh(params: &OtaParams) -> Result<Self> {
+        let _ = remove_file(UPDATE_BIN_PATH);
+        let _ = remove_file(UPDATE_MAN_PATH);
+        File::create(UPDATE_BIN_PATH)?;
+        fs::write(UPDATE_HASH_PATH, &params.sha256_hash)?;
+        Ok(Self::new())
+    }
+    // pick up a partial update.bin, if it belongs to the same image
+    fn resume(params: &OtaParams) -> Result<Self> {
+        let same_image = fs::read_to_string(UPDATE_HASH_PATH)
+            .map(|h| h == params.sha256_hash)
+            .unwrap_or(false);
+        if !same_image {
+            return Self::fresh(params);
+        }
+        let Ok(file) = File::open(UPDATE_BIN_PATH) else {
+            return Self::fresh(params);
+        };
+        let mut dl = Self::new();
+        let mut reader = BufReader::new(file);
+        dl.written = std::io::copy(&mut reader, &mut dl.engine)? as u32;
+        info!("Resuming the update from {} bytes", dl.written);
+        Ok(dl)
+    }
+    fn write(&mut self, writer: &mut impl Write, data: &[u8]) -> Result<()> {
+        writer.write_all(data)?;
+        self.engine.input(data);
+        self.written += data.len() as u32;
+        Ok(())
+    }
+    fn progress(&self, stage: Stage) -> Progress {
+        Progress::new(stage, self.written, self.total.unwrap_or(0))
+    }
+    fn check_integrity(&self, params: &OtaParams) -> Result<()> {
+        crate::bitcoin_utils::check_hash(self.engine.clone(), &params.sha256_hash)
+    }
+}
+
+// true if the last write passed a progress mark
+pub fn progress_due(before: u32, after: u32) -> bool {
+    before / PROGRESS_EVERY != after / PROGRESS_EVERY
+}
+
+fn get_update(params: &OtaParams, report: &mut dyn FnMut(Progress)) -> Result<Download> {
+    let mut dl = Download::resume(params)?;
+    let mut attempt = 1;
+    loop {
+        match download(params, &mut dl, report) {
+            Ok(()) => break,
+            Err(e) if attempt < DOWNLOAD_ATTEMPTS => {
+                warn!("Download attempt {} failed: {:?}", attempt, e);
+                attempt += 1;
+                thread::sleep(Duration::from_secs(2));
+            }
+            Err(e) => {
+                report(dl.progress(Stage::Failed));
+                return Err(e);
+            }
+        }
+    }
+    info!("TOTAL written: {}", dl.written);
+    Ok(dl)
+}
+
+// fetches the rest of the image, from where the file on the sdcard ends
+fn download(params: &OtaParams, dl: &mut Download, report: &mut dyn FnMut(Progress)) -> Result<()> {
     let configuration = Configuration {
         buffer_size: Some(BUFFER_LEN),
         buffer_size_tx: Some(BUFFER_LEN / 3),
         follow_redirects_policy: FollowNone,
         crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        timeout: Some(HTTP_TIMEOUT),
         ..Default::default()
     };
     let mut reader = EspHttpConnection::new(&configuration)?;
     let full_url = params_to_url(params);
-    reader.initiate_request(Method::Get, &full_url, &[])?;
+    let range = format!("bytes={}-", dl.written);
+    let headers = if dl.written > 0 {
+        vec![("Range", range.as_str())]
+    } else {
+        vec![]
+    };
+    reader.initiate_request(Method::Get, &full_url, &headers)?;
     reader.initiate_response()?;
-    // let mut reader = response.reader();
+    match reader.status() {
+        206 => (),
+        200 => {
+            if dl.written > 0 {
+                info!("Server ignored the Range header, starting over");
+                *dl = Download::fresh(params)?;
+            }
+        }
+        // nothing left past the end of the file
+        416 if dl.written > 0 => return Ok(()),
+        status => return Err(anyhow!("got {} code when fetching update", status)),
+    }
+    dl.total = total_len(&reader, dl.written);
 
-    let _ = remove_file(UPDATE_BIN_PATH);
-    let file = File::create(UPDATE_BIN_PATH)?;
+    let file = OpenOptions::new().append(true).open(UPDATE_BIN_PATH)?;
     let mut writer = BufWriter::new(file);
-
     let mut buf = [0_u8; BUFFER_LEN];
-    let mut read_tot: usize = 0;
-    let mut write_tot: usize = 0;
     loop {
         let r = reader.read(&mut buf)?;
         if r == 0 {
             break;
         }
-        let w = writer.write(&buf[..r])?;
-        read_tot += r;
-        write_tot += w;
+        let before = dl.written;
+        dl.write(&mut writer, &buf[..r])?;
+        if progress_due(before, dl.written) {
+            report(dl.progress(Stage::Receiving));
+        }
+    }
+    writer.flush()?;
+    if let Some(total) = dl.total {
+        if dl.written < total {
+            return Err(anyhow!(
+                "connection closed at {} of {} bytes",
+                dl.written,
+                total
+            ));
+        }
     }
-    info!("TOTAL read: {}", read_tot);
-    info!("TOTAL written: {}", write_tot);
     Ok(())
 }
 
-fn check_signature(params: &OtaParams) -> Result<()> {
-    crate::bitcoin_utils::check_signature(&params.sha256_hash, &params.message_sig)
+// "Content-Range: bytes 100-199/200" on a resumed request, else Content-Length
+fn total_len(reader: &EspHttpConnection, offset: u32) -> Option<u32> {
+    if let Some(range) = reader.header("Content-Range") {
+        return range.rsplit('/').next()?.parse().ok();
+    }
+    let len: u32 = reader.header("Content-Length")?.parse().ok()?;
+    Some(offset + len)
 }
 
-fn check_integrity(params: &OtaParams) -> Result<()> {
-    crate::bitcoin_utils::check_integrity(UPDATE_BIN_PATH, &params.sha256_hash)
+// the release keys sign over the chip, version and hash, see release::verify_release.
+// All the signatures go in message_sig, separated by whitespace
+fn check_signature(params: &OtaParams, min_version: u64, keys: &KeySet) -> Result<()> {
+    verify_release(
+        keys,
+        params.version,
+        &params.sha256_hash,
+        &params.message_sig,
+        min_version,
+    )
+    .map_err(|e| anyhow!("Failed signature check: {}", e))?;
+    if params.version < min_version {
+        info!(
+            "Downgrade from minimum version {} to {} is authorized",
+            min_version, params.version
+        );
+    }
+    Ok(())
 }
 
-pub fn update_sphinx_key(params: &OtaParams) -> Result<()> {
+// the factory app checks the image against this before flashing it
+fn write_manifest(params: &OtaParams) -> Result<()> {
+    let manifest = Manifest {
+        version: params.version,
+        sha256: &params.sha256_hash,
+        sigs: &params.message_sig,
+    };
+    let mut contents = String::new();
+    manifest.write(&mut contents)?;
+    fs::write(UPDATE_MAN_PATH, contents)?;
+    Ok(())
+}
+
+pub fn update_sphinx_key(params: &OtaParams, report: &mut dyn FnMut(Progress)) -> Result<()> {
     info!("Getting the update...");
-    get_update(params)?;
+    let dl = get_update(params, report)?;
     info!("Update written to sd card, checking integrity...");
-    check_integrity(params)?;
+    report(dl.progress(Stage::Verifying));
+    if let Err(e) = dl.check_integrity(params) {
+        // don't resume from a corrupt file next time
+        let _ = remove_file(UPDATE_HASH_PATH);
+        report(dl.progress(Stage::Failed));
+        return Err(e);
+    }
+    let _ = remove_file(UPDATE_HASH_PATH);
+    write_manifest(params)?;
     info!("Integrity check passed, performing factory reset...");
+    report(dl.progress(Stage::Installing));
     factory_reset()?;
     info!("Factory reset completed!");
     Ok(())
 }
 
-pub fn validate_ota_message(params: &OtaParams) -> Result<()> {
+pub fn validate_ota_message(params: &OtaParams, min_version: u64, keys: &KeySet) -> Result<()> {
     info!("Checking signature...");
-    check_signature(params)?;
+    check_signature(params, min_version, keys)?;
+    if is_mqtt_update(params) {
+        info!("Good signature, waiting for the broker to push the update...");
+        return Ok(());
+    }
     info!("Good signature, checking url...");
     let configuration = Configuration {
         buffer_size: Some(BUFFER_LEN / 3),
@@ -117,6 +299,78 @@ pub fn validate_ota_message(params: &OtaParams) -> Result<()> {
     }
 }
 
+pub fn is_mqtt_update(params: &OtaParams) -> bool {
+    params.url == ota_proto::MQTT_URL
+}
+
+// an update pushed by the broker in chunks, instead of pulled over HTTP
+pub struct MqttUpdate {
+    params: OtaParams,
+    writer: BufWriter<File>,
+    dl: Download,
+}
+
+impl MqttUpdate {
+    pub fn start(params: &OtaParams) -> Result<Self> {
+        let dl = Download::fresh(params)?;
+        let file = OpenOptions::new().append(true).open(UPDATE_BIN_PATH)?;
+        Ok(Self {
+            params: params.clone(),
+            writer: BufWriter::new(file),
+            dl,
+        })
+    }
+    // returns the next expected offset, to ack the chunk with
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<u32> {
+        let (offset, total, data) =
+            ota_proto::parse_chunk(chunk).ok_or_else(|| anyhow!("malformed OTA chunk"))?;
+        if total > ota_proto::MAX_IMAGE_LEN {
+            return Err(anyhow!("OTA image is too big: {} bytes", total));
+        }
+        if *self.dl.total.get_or_insert(total) != total {
+            return Err(anyhow!("OTA image size changed mid-update"));
+        }
+        if offset < self.dl.written {
+            // redelivered, already written
+            return Ok(self.dl.written);
+        }
+        if offset > self.dl.written {
+            return Err(anyhow!(
+                "OTA chunk at {} but only {} received",
+                offset,
+                self.dl.written
+            ));
+        }
+        if self.dl.written as usize + data.len() > total as usize {
+            return Err(anyhow!("OTA chunk runs past the end of the image"));
+        }
+        self.dl.write(&mut self.writer, data)?;
+        Ok(self.dl.written)
+    }
+    pub fn version(&self) -> u64 {
+        self.params.version
+    }
+    pub fn is_complete(&self) -> bool {
+        self.dl.total == Some(self.dl.written)
+    }
+    pub fn progress(&self, stage: Stage) -> Progress {
+        self.dl.progress(stage)
+    }
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        drop(self.writer);
+        info!("TOTAL received: {}", self.dl.written);
+        info!("Update written to sd card, checking integrity...");
+        self.dl.check_integrity(&self.params)?;
+        let _ = remove_file(UPDATE_HASH_PATH);
+        write_manifest(&self.params)?;
+        info!("Integrity check passed, performing factory reset...");
+        factory_reset()?;
+        info!("Factory reset completed!");
+        Ok(())
+    }
+}
+
 fn params_to_url(params: &OtaParams) -> String {
     let mut url = params.url.clone();
     url.push_str(&params.version.to_string());
@@ -1,3 +1,6 @@
+use sphinx_key_common::ext::ResetMode;
+use sphinx_key_common::led::ErrorCode;
+
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Copy)]
 pub enum Status {
     Waiting,
@@ -10,6 +13,8 @@ pub enum Status {
     ConnectingToMqtt,
     Connected,
     Signing,
+    // a payment is waiting on the button
+    Approving,
     Ota,
     Reset1a,
     Reset1,
@@ -17,4 +22,14 @@ pub enum Status {
     Reset2,
     Reset3a,
     Reset3,
+    // picking what to wipe, with the button or a control msg
+    Reset(ResetMode),
+    // wiped, about to restart
+    Wiped(ResetMode),
+    // the current status again, for a few long blinks
+    ShowStatus,
+    // a blink code over the current status, see common/src/led.rs
+    Error(ErrorCode),
+    // of the LED, from NVS or a control msg
+    Brightness(u8),
 }
@@ -1,9 +1,15 @@
+mod seed;
+
 use dotenv::dotenv;
 use rand::{rngs::OsRng, thread_rng, RngCore};
 use serde::{Deserialize, Serialize};
-use sphinx_crypter::chacha::{encrypt, MSG_LEN, NONCE_LEN};
+use sphinx_crypter::chacha::{encrypt, NONCE_LEN};
 use sphinx_crypter::ecdh::{derive_shared_secret_from_slice, PUBLIC_KEY_LEN};
 use sphinx_crypter::secp256k1::Secp256k1;
+use sphinx_key_common::provision::BACKUP_FILE_NAME;
+use sphinx_key_common::setup::{
+    DeviceStatus, GenerateRequest, GenerateResponse, ScanEntry, Unlock,
+};
 use sphinx_signer::sphinx_glyph::control::Config;
 use std::convert::TryInto;
 use std::env;
@@ -26,6 +32,8 @@ pub struct ConfigBody {
     pub broker: String,
     pub pubkey: String, // for ecdh
     pub network: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pin: Option<String>,
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ConfigResponse {
@@ -38,11 +46,7 @@ async fn main() -> anyhow::Result<()> {
 
     let url: String = env::var("URL").unwrap_or(URL.to_string());
 
-    let ssid: String = env::var("SSID").expect("no ssid");
-    let pass: String = env::var("PASS").expect("no pass");
     let broker: String = env::var("BROKER").expect("no broker");
-    let seed_string: String = env::var("SEED").expect("no seed");
-    let seed: [u8; MSG_LEN] = hex::decode(seed_string)?[..MSG_LEN].try_into()?;
     let network: String = env::var("NETWORK").unwrap_or("regtest".to_string());
     if !(network == "bitcoin"
         || network == "mainnet"
@@ -62,7 +66,57 @@ async fn main() -> anyhow::Result<()> {
         .build()
         .expect("couldnt build reqwest client");
 
-    let conf_string = if !WIFI_RESET {
+    let status: DeviceStatus = client
+        .get(format!("{}/{}", url, "status"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    println!("Signer status: {:?}", status);
+
+    // a PIN locked signer waits for the PIN at boot, instead of a config
+    if status.locked {
+        let unlock = Unlock {
+            pin: env::var("PIN").expect("no pin"),
+        };
+        let res = client
+            .post(format!("{}/{}", url, "unlock"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&unlock)?)
+            .send()
+            .await?;
+        println!("Unlock: {}", res.text().await?);
+        return Ok(());
+    }
+
+    // pick from the networks the signer can see, if SSID is not set
+    let ssid: String = match env::var("SSID") {
+        Ok(ssid) => ssid,
+        Err(_) => pick_network(&client, &url).await?,
+    };
+    let pass: String = env::var("PASS").expect("no pass");
+
+    // the signer makes the seed itself, and hands back a backup sealed to BACKUP_PUBKEY
+    let generate = !WIFI_RESET && !status.has_seed && env::var("BACKUP_PUBKEY").is_ok();
+    if generate {
+        let req = GenerateRequest {
+            network: network.clone(),
+            backup_pubkey: env::var("BACKUP_PUBKEY")?,
+        };
+        let res: GenerateResponse = client
+            .post(format!("{}/{}", url, "generate"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?
+            .json()
+            .await?;
+        println!("NODE PUBKEY {}", res.node_pubkey);
+        std::fs::write(BACKUP_FILE_NAME, serde_json::to_string_pretty(&res.backup)?)?;
+        println!("Seed backup written to {}", BACKUP_FILE_NAME);
+    }
+
+    let conf_string = if !WIFI_RESET && !status.has_seed && !generate {
         let res = client
             .get(format!("{}/{}", url, "ecdh"))
             .header("Content-Type", "application/json")
@@ -78,7 +132,13 @@ async fn main() -> anyhow::Result<()> {
 
         let mut nonce_end = [0; NONCE_LEN];
         OsRng.fill_bytes(&mut nonce_end);
-        let cipher = encrypt(seed, shared_secret, nonce_end)?;
+        let cipher = encrypt(seed::read_seed()?, shared_secret, nonce_end)?;
+
+        // the signer burns a key into its eFuse for the PIN, which can't be
+        // undone, so that has to be asked for as well
+        if env::var("PIN").is_ok() && env::var("BURN_PIN_KEY").is_err() {
+            anyhow::bail!("a PIN needs BURN_PIN_KEY=1, to burn the PIN key into eFuse");
+        }
 
         let cipher_seed = hex::encode(cipher);
         let config = ConfigBody {
@@ -88,6 +148,7 @@ async fn main() -> anyhow::Result<()> {
             broker,
             network,
             pubkey: hex::encode(pk1.serialize()),
+            pin: env::var("PIN").ok(),
         };
         serde_json::to_string(&config)?
     } else {
@@ -100,10 +161,11 @@ async fn main() -> anyhow::Result<()> {
         serde_json::to_string(&config)?
     };
 
-    let conf_encoded = urlencoding::encode(&conf_string).into_owned();
-    println!("Posting seed");
+    println!("Posting config");
     let res2 = client
-        .post(format!("{}/{}={}", url, "config?config", conf_encoded))
+        .post(format!("{}/{}", url, "config"))
+        .header("Content-Type", "application/json")
+        .body(conf_string)
         .send()
         .await?;
     let conf_res: ConfigResponse = res2.json().await?;
@@ -113,3 +175,24 @@ async fn main() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+async fn pick_network(client: &reqwest::Client, url: &str) -> anyhow::Result<String> {
+    let networks: Vec<ScanEntry> = client
+        .get(format!("{}/{}", url, "scan"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if networks.is_empty() {
+        anyhow::bail!("the signer sees no networks, set SSID instead");
+    }
+    for (i, n) in networks.iter().enumerate() {
+        println!("{}: {} ({} dBm, {})", i, n.ssid, n.rssi, n.auth);
+    }
+    println!("Pick a network:");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let i: usize = line.trim().parse()?;
+    let network = networks.get(i).ok_or(anyhow::anyhow!("no network {}", i))?;
+    Ok(network.ssid.clone())
+}
@@ -37,11 +37,22 @@ async fn main() -> anyhow::Result<()> {
         .build()
         .expect("couldnt build reqwest client");
 
-    let res = client
-        .post(format!("{}/control?msg={}&cid={}", broker_url, msg_hex, "df106bf2092378bba4f49058cdbec2bf"))
-        .header("Content-Type", "application/json")
-        .send()
-        .await?;
+    let res = if let Ok(image_path) = env::var("OTA_IMAGE") {
+        // push the image through the broker, cmd.json must have "url": "mqtt"
+        let image = std::fs::read(image_path)?;
+        client
+            .post(format!("{}/ota?msg={}&cid={}", broker_url, msg_hex, "df106bf2092378bba4f49058cdbec2bf"))
+            .timeout(Duration::from_secs(600))
+            .body(image)
+            .send()
+            .await?
+    } else {
+        client
+            .post(format!("{}/control?msg={}&cid={}", broker_url, msg_hex, "df106bf2092378bba4f49058cdbec2bf"))
+            .header("Content-Type", "application/json")
+            .send()
+            .await?
+    };
 
     let response: String = res.text().await?;
     let res_bytes = hex::decode(response).expect("couldnt decode response");
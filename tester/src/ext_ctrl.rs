@@ -0,0 +1,55 @@
+use dotenv::dotenv;
+use sphinx_key_common::ext::{ExtControl, ExtResponse};
+use sphinx_key_common::provision::{SealedSeed, BACKUP_FILE_NAME};
+use sphinx_signer::lightning_signer::bitcoin::Network;
+use sphinx_signer::sphinx_glyph::sphinx_auther::nonce;
+use std::env;
+use std::time::Duration;
+
+const DEFAULT_URL: &str = "http://localhost:8000/api";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv().ok();
+
+    let nonce_string: String = env::var("NONCE").unwrap_or("0".to_string());
+    let nonce: u64 = nonce_string.parse::<u64>().expect("failed to parse nonce");
+
+    let broker_url: String = env::var("BROKER_URL").unwrap_or(DEFAULT_URL.to_string());
+    println!("{}", broker_url);
+
+    let seed_string: String = env::var("SEED").expect("no seed");
+    let seed = hex::decode(seed_string).expect("yo");
+    let (_pk, sk) = sphinx_signer::derive_node_keys(&Network::Regtest, &seed);
+
+    let command = serde_json::from_str::<ExtControl>(&std::fs::read_to_string("./ext_cmd.json").unwrap()).unwrap();
+    println!("COMMAND! {:?}", command);
+
+    // same nonce counter as the ctrl messages
+    let msg = nonce::build_msg(&serde_json::to_vec(&command)?, &sk, nonce + 1)?;
+    let msg_hex = hex::encode(&msg);
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("couldnt build reqwest client");
+
+    let res = client
+        .post(format!("{}/ext_control?msg={}&cid={}", broker_url, msg_hex, "df106bf2092378bba4f49058cdbec2bf"))
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+
+    let response: String = res.text().await?;
+    let resp = serde_json::from_str::<ExtResponse>(&response).expect("nope");
+    println!("RESponse from the ESP!!! {:?}", resp);
+
+    // restore it onto a new signer with RESTORE=backup.json and the BACKUP_SECRET
+    if let ExtResponse::SeedBackup { pubkey, cipher } = resp {
+        let backup = SealedSeed::Device { pubkey, cipher };
+        std::fs::write(BACKUP_FILE_NAME, serde_json::to_string_pretty(&backup)?)?;
+        println!("Seed backup written to {}", BACKUP_FILE_NAME);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+mod seed;
+
+use dotenv::dotenv;
+use rand::{rngs::OsRng, thread_rng, RngCore};
+use sphinx_crypter::chacha::{encrypt, NONCE_LEN};
+use sphinx_crypter::ecdh::{derive_shared_secret_from_slice, PUBLIC_KEY_LEN};
+use sphinx_crypter::secp256k1::Secp256k1;
+use sphinx_key_common::provision::{Provision, SealedSeed, FILE_NAME};
+use std::convert::TryInto;
+use std::env;
+
+// writes a provision.json to copy onto the sdcard of a signer. The seed is
+// sealed to the signer's device.pub, so the file only works for that signer
+fn main() -> anyhow::Result<()> {
+    dotenv().ok();
+
+    let ssid: String = env::var("SSID").expect("no ssid");
+    let pass: String = env::var("PASS").expect("no pass");
+    let broker: String = env::var("BROKER").expect("no broker");
+    let seed = seed::read_seed()?;
+    let network: String = env::var("NETWORK").unwrap_or("regtest".to_string());
+
+    let mut nonce_end = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce_end);
+    // either the hex pubkey or the path to a device.pub
+    let device_pub: String = env::var("DEVICE_PUB").expect("no DEVICE_PUB");
+    let device_pub = std::fs::read_to_string(&device_pub).unwrap_or(device_pub);
+    let their_pk: [u8; PUBLIC_KEY_LEN] = hex::decode(device_pub.trim())?[..].try_into()?;
+    let s = Secp256k1::new();
+    let (sk1, pk1) = s.generate_keypair(&mut thread_rng());
+    let shared_secret = derive_shared_secret_from_slice(their_pk, sk1.secret_bytes())?;
+    let cipher = encrypt(seed, shared_secret, nonce_end)?;
+    let sealed = SealedSeed::Device {
+        pubkey: hex::encode(pk1.serialize()),
+        cipher: hex::encode(cipher),
+    };
+
+    let provision = Provision {
+        broker,
+        ssid,
+        pass,
+        network,
+        seed: Some(sealed),
+    };
+    std::fs::write(FILE_NAME, serde_json::to_string_pretty(&provision)?)?;
+    println!("wrote {}", FILE_NAME);
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use sphinx_crypter::chacha::{decrypt, MSG_LEN, PAYLOAD_LEN};
+use sphinx_crypter::ecdh::{derive_shared_secret_from_slice, PUBLIC_KEY_LEN};
+use sphinx_key_common::mnemonic::seed_from_mnemonic;
+use sphinx_key_common::provision::SealedSeed;
+use std::convert::TryInto;
+use std::env;
+
+// from RESTORE (a backup file, opened with BACKUP_SECRET), MNEMONIC, or SEED
+pub fn read_seed() -> anyhow::Result<[u8; MSG_LEN]> {
+    if let Ok(path) = env::var("RESTORE") {
+        let backup: SealedSeed = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let secret: String = env::var("BACKUP_SECRET").expect("no backup secret");
+        return unseal_backup(&backup, &hex::decode(secret)?);
+    }
+    Ok(match env::var("MNEMONIC") {
+        Ok(phrase) => {
+            let passphrase = env::var("MNEMONIC_PASSPHRASE").unwrap_or_default();
+            seed_from_mnemonic(&phrase, &passphrase)
+                .map_err(|e| anyhow::anyhow!("bad mnemonic: {}", e))?
+        }
+        Err(_) => {
+            let seed_string: String = env::var("SEED").expect("no seed or mnemonic");
+            hex::decode(seed_string)?[..MSG_LEN].try_into()?
+        }
+    })
+}
+
+pub fn unseal_backup(backup: &SealedSeed, secret: &[u8]) -> anyhow::Result<[u8; MSG_LEN]> {
+    let SealedSeed::Device { pubkey, cipher } = backup;
+    let their_pk: [u8; PUBLIC_KEY_LEN] = hex::decode(pubkey)?[..].try_into()?;
+    let shared_secret = derive_shared_secret_from_slice(their_pk, secret.try_into()?)?;
+    let cipher: [u8; PAYLOAD_LEN] = hex::decode(cipher)?[..].try_into()?;
+    Ok(decrypt(cipher, shared_secret)?)
+}
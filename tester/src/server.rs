@@ -10,6 +10,7 @@ use sphinx_crypter::ecdh::{derive_shared_secret_from_slice, PUBLIC_KEY_LEN};
 use sphinx_crypter::secp256k1::rand::thread_rng;
 use sphinx_crypter::secp256k1::Secp256k1;
 use sphinx_crypter::secp256k1::{PublicKey, SecretKey};
+use sphinx_key_common::setup::{DeviceStatus, ScanEntry};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ConfigBody {
@@ -48,13 +49,40 @@ fn config(keys: &State<Keys>, config: &str) -> String {
     "{\"success\":true}".to_string()
 }
 
+#[post("/config", data = "<body>", rank = 2)]
+fn config_body(keys: &State<Keys>, body: String) -> String {
+    config(keys, &body)
+}
+
+#[get("/scan")]
+fn scan() -> String {
+    let networks = vec![ScanEntry {
+        ssid: "sphinx-test".to_string(),
+        rssi: -50,
+        auth: "WPA2Personal".to_string(),
+    }];
+    serde_json::to_string(&networks).unwrap()
+}
+
+#[get("/status")]
+fn status(keys: &State<Keys>) -> String {
+    let status = DeviceStatus {
+        firmware: env!("CARGO_PKG_VERSION").to_string(),
+        ota_version: None,
+        has_seed: false,
+        pubkey: hex::encode(keys.pk.serialize()),
+        locked: false,
+    };
+    serde_json::to_string(&status).unwrap()
+}
+
 pub fn decrypt_seed(dto: ConfigBody, sk1: SecretKey) -> anyhow::Result<Config> {
     let their_pk = hex::decode(dto.pubkey)?;
-    let their_pk_bytes: [u8; PUBLIC_KEY_LEN] = their_pk[..PUBLIC_KEY_LEN].try_into()?;
+    let their_pk_bytes: [u8; PUBLIC_KEY_LEN] = their_pk.as_slice().try_into()?;
     let shared_secret = derive_shared_secret_from_slice(their_pk_bytes, sk1.secret_bytes())?;
     // decrypt seed
     let cipher_seed = hex::decode(dto.seed)?;
-    let cipher: [u8; PAYLOAD_LEN] = cipher_seed[..PAYLOAD_LEN].try_into()?;
+    let cipher: [u8; PAYLOAD_LEN] = cipher_seed.as_slice().try_into()?;
     let seed = decrypt(cipher, shared_secret)?;
 
     Ok(Config {
@@ -71,6 +99,6 @@ fn rocket() -> _ {
     let s = Secp256k1::new();
     let (sk, pk) = s.generate_keypair(&mut thread_rng());
     rocket::build()
-        .mount("/", routes![ecdh, config])
+        .mount("/", routes![ecdh, config, config_body, scan, status])
         .manage(Keys { sk, pk })
 }